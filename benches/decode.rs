@@ -0,0 +1,100 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Decoding and header probing. Decode throughput is the size of the image
+//! as RGBA8, so that options producing different layouts compare fairly.
+//! Run a subset with `cargo bench --bench decode -- <filter>`.
+
+#[macro_use]
+extern crate bencher;
+extern crate png;
+
+use std::fs::File;
+use std::io::Read;
+
+use bencher::{black_box, Bencher};
+use png::{Composite, DecodeOptions, GrayWeights, PixelFormat};
+
+const SCREENSHOT: &str = "test/servo-screenshot.png";
+const DINO: &str = "test/mozilla-dinosaur-head-logo.png";
+const RUST: &str = "test/rust-huge-logo.png";
+
+fn read(path: &str) -> Vec<u8> {
+    let mut data = vec![];
+    match File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+        Ok(_) => data,
+        Err(e) => panic!("could not read '{}': {}", path, e),
+    }
+}
+
+fn bench_decode(b: &mut Bencher, path: &str, options: &DecodeOptions) {
+    let data = read(path);
+    let image = png::load_png_from_memory_with_options(&data, options).unwrap();
+    b.bytes = image.width as u64 * image.height as u64 * 4;
+    b.iter(|| png::load_png_from_memory_with_options(&data, options).unwrap());
+}
+
+fn decode_screenshot(b: &mut Bencher) {
+    bench_decode(b, SCREENSHOT, &DecodeOptions::default());
+}
+
+fn decode_dino(b: &mut Bencher) {
+    bench_decode(b, DINO, &DecodeOptions::default());
+}
+
+fn decode_rust(b: &mut Bencher) {
+    bench_decode(b, RUST, &DecodeOptions::default());
+}
+
+fn decode_bgra(b: &mut Bencher) {
+    bench_decode(b, SCREENSHOT, &DecodeOptions { format: Some(PixelFormat::BGRA8), ..Default::default() });
+}
+
+fn decode_to_gray(b: &mut Bencher) {
+    bench_decode(b, SCREENSHOT, &DecodeOptions { to_gray: Some(GrayWeights::Rec709), ..Default::default() });
+}
+
+fn decode_composite(b: &mut Bencher) {
+    bench_decode(b, DINO, &DecodeOptions {
+        composite: Some(Composite::FileBackground([255, 255, 255])),
+        ..Default::default()
+    });
+}
+
+fn decode_into(b: &mut Bencher) {
+    let data = read(SCREENSHOT);
+    let (width, height) = png::dimensions(&data).unwrap();
+    let stride = width as usize * 4;
+    let mut dst = vec![0; stride * height as usize];
+    b.bytes = dst.len() as u64;
+    b.iter(|| png::decode_into(&data, &mut dst, stride, PixelFormat::RGBA8).unwrap());
+}
+
+// Probing reads a fixed number of bytes whatever the image size, so these
+// report time per call only.
+
+fn probe_is_png(b: &mut Bencher) {
+    let data = read(RUST);
+    b.iter(|| png::is_png(black_box(&data)));
+}
+
+fn probe_dimensions(b: &mut Bencher) {
+    let data = read(RUST);
+    b.iter(|| png::dimensions(black_box(&data)).unwrap());
+}
+
+fn probe_chunks(b: &mut Bencher) {
+    let data = read(SCREENSHOT);
+    b.iter(|| png::chunks::chunks(black_box(&data)).unwrap().count());
+}
+
+benchmark_group!(decode, decode_screenshot, decode_dino, decode_rust);
+benchmark_group!(decode_with_options, decode_bgra, decode_to_gray, decode_composite, decode_into);
+benchmark_group!(probe, probe_is_png, probe_dimensions, probe_chunks);
+benchmark_main!(decode, decode_with_options, probe);
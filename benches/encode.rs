@@ -0,0 +1,43 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Encoding at each zlib compression level. Throughput is the size of the
+//! image as RGBA8.
+
+#[macro_use]
+extern crate bencher;
+extern crate png;
+
+use bencher::Bencher;
+use png::EncodeOptions;
+
+fn bench_encode(b: &mut Bencher, compression_level: Option<u8>) {
+    let mut image = png::load_png("test/servo-screenshot.png").unwrap();
+    let options = EncodeOptions { compression_level, ..Default::default() };
+    b.bytes = image.width as u64 * image.height as u64 * 4;
+    b.iter(|| png::to_vec_with_options(&mut image, &options).unwrap());
+}
+
+fn encode_default(b: &mut Bencher) {
+    bench_encode(b, None);
+}
+
+macro_rules! level_benches {
+    ($($name:ident: $level:expr),+) => {
+        $(fn $name(b: &mut Bencher) {
+            bench_encode(b, Some($level));
+        })+
+        benchmark_group!(encode, encode_default, $($name),+);
+    }
+}
+
+level_benches!(encode_level_0: 0, encode_level_1: 1, encode_level_2: 2, encode_level_3: 3, encode_level_4: 4,
+               encode_level_5: 5, encode_level_6: 6, encode_level_7: 7, encode_level_8: 8, encode_level_9: 9);
+
+benchmark_main!(encode);
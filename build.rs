@@ -1,18 +1,23 @@
-extern crate gcc;
+extern crate cc;
 
 use std::env;
-use std::path::PathBuf;
 
 fn main() {
-    let mut cfg: gcc::Config = gcc::Config::new();
+    let mut cfg = cc::Build::new();
 
     cfg.file("src/shim.c");
 
-    let src_dir = PathBuf::from(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("png-sys/libpng-1.6.16");
-    cfg.include(&src_dir);
-
-    let dep_dir = PathBuf::from(&env::var("DEP_PNG_ROOT").unwrap());
-    cfg.include(&dep_dir);
+    // libpng's headers, with pnglibconf.h for the vendored build.
+    for dir in env::split_paths(&env::var("DEP_PNG_INCLUDE").unwrap()) {
+        cfg.include(dir);
+    }
 
     cfg.compile("libpngshim.a");
+
+    // src/ffi.rs links to the prefixed names when png-sys built its own
+    // libpng.
+    println!("cargo:rustc-check-cfg=cfg(png_prefix)");
+    if env::var_os("DEP_PNG_PREFIX").is_some() {
+        println!("cargo:rustc-cfg=png_prefix");
+    }
 }
@@ -0,0 +1,41 @@
+#![no_main]
+
+//! Decodes with options chosen by the last byte, so that libpng's
+//! transformations are covered as well.
+
+use libfuzzer_sys::fuzz_target;
+use png::{Composite, DecodeOptions, GrayWeights, PixelFormat};
+
+/// Larger images only test how much memory the machine has.
+const MAX_PIXELS: u64 = 1 << 22;
+
+const FORMATS: [PixelFormat; 11] = [PixelFormat::K8, PixelFormat::KA8, PixelFormat::RGB8, PixelFormat::RGBA8,
+                                    PixelFormat::BGR8, PixelFormat::BGRA8, PixelFormat::ARGB8, PixelFormat::K16,
+                                    PixelFormat::KA16, PixelFormat::RGB16, PixelFormat::RGBA16];
+
+fuzz_target!(|data: &[u8]| {
+    let (width, height) = match png::dimensions(data) {
+        Ok(dimensions) => dimensions,
+        Err(_) => (0, 0),
+    };
+    // Invalid zero dimensions still size the buffer for `decode_into`.
+    if (width as u64 + 1).saturating_mul(height as u64 + 1) > MAX_PIXELS {
+        return;
+    }
+    let flags = data.last().cloned().unwrap_or(0);
+    let options = DecodeOptions {
+        auto_orient: flags & 1 != 0,
+        composite: if flags & 2 != 0 { Some(Composite::FileBackground([1, 2, 3])) } else { None },
+        to_gray: if flags & 4 != 0 { Some(GrayWeights::Rec709) } else { None },
+        keep_16_bit: flags & 8 != 0,
+        shift_to_significant_bits: flags & 16 != 0,
+        format: if flags & 32 != 0 { Some(FORMATS[flags as usize % FORMATS.len()]) } else { None },
+    };
+    let _ = png::load_png_from_memory_with_options(data, &options);
+
+    // Into a strided buffer that may be too small.
+    let format = FORMATS[(flags >> 4) as usize % FORMATS.len()];
+    let stride = width as usize * format.bytes_per_pixel() + (flags & 3) as usize;
+    let mut dst = vec![0; (stride * height as usize).saturating_sub((flags >> 6) as usize)];
+    let _ = png::decode_into(data, &mut dst, stride, format);
+});
@@ -0,0 +1,92 @@
+#![no_main]
+
+//! Encodes images whose layout, dimensions, settings and pixels all come
+//! from the input, so that most of them are invalid. Whatever encodes must
+//! decode again.
+
+use libfuzzer_sys::fuzz_target;
+use png::{BitDepth, Background, EncodeOptions, Filter, Image, Metadata, PaletteEntry, PixelsByColorType};
+use png::{SignificantBits, Strategy};
+
+fn palette(data: &[u8]) -> Vec<PaletteEntry> {
+    data.chunks(4).filter(|entry| entry.len() == 4).map(|entry| {
+        PaletteEntry { red: entry[0], green: entry[1], blue: entry[2], alpha: entry[3] }
+    }).collect()
+}
+
+fn pixels(layout: u8, data: &[u8]) -> PixelsByColorType {
+    let bytes = data.to_vec();
+    let words = data.chunks(2).map(|pair| pair[0] as u16 * 256 + *pair.last().unwrap() as u16).collect();
+    // The palette comes first, with its length in the first byte.
+    let split = data.first().map_or(0, |&len| (1 + len as usize * 4).min(data.len()));
+    let (entries, indices) = data.split_at(split);
+    let entries = palette(entries.get(1..).unwrap_or(&[]));
+    let indices = indices.to_vec();
+    match layout % 18 {
+        0 => PixelsByColorType::K8(bytes),
+        1 => PixelsByColorType::KA8(bytes),
+        2 => PixelsByColorType::RGB8(bytes),
+        3 => PixelsByColorType::RGBA8(bytes),
+        4 => PixelsByColorType::BGR8(bytes),
+        5 => PixelsByColorType::BGRA8(bytes),
+        6 => PixelsByColorType::ARGB8(bytes),
+        7 => PixelsByColorType::K16(words),
+        8 => PixelsByColorType::KA16(words),
+        9 => PixelsByColorType::RGB16(words),
+        10 => PixelsByColorType::RGBA16(words),
+        11 => PixelsByColorType::K1(bytes),
+        12 => PixelsByColorType::K2(bytes),
+        13 => PixelsByColorType::K4(bytes),
+        14 => PixelsByColorType::I1(indices, entries),
+        15 => PixelsByColorType::I2(indices, entries),
+        16 => PixelsByColorType::I4(indices, entries),
+        _ => PixelsByColorType::I8(indices, entries),
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 8 {
+        return;
+    }
+    let (header, rest) = data.split_at(8);
+    let metadata = Metadata {
+        background: match header[4] % 4 {
+            0 => None,
+            1 => Some(Background::Gray(header[5] as u16)),
+            2 => Some(Background::Rgb(header[5] as u16, header[6] as u16, header[7] as u16)),
+            _ => Some(Background::Index(header[5])),
+        },
+        significant_bits: if header[4] & 4 != 0 {
+            let bits = header[6] % 17;
+            Some(SignificantBits { red: bits, green: bits, blue: bits, gray: bits, alpha: header[7] % 17 })
+        } else {
+            None
+        },
+        ..Default::default()
+    };
+    let mut img = Image {
+        width: header[1] as u32,
+        height: header[2] as u32,
+        pixels: pixels(header[0], rest),
+        metadata,
+    };
+    let flags = header[3];
+    let options = EncodeOptions {
+        shift_from_significant_bits: flags & 1 != 0,
+        bit_depth: match flags >> 1 & 3 {
+            0 => BitDepth::Native,
+            1 => BitDepth::Smallest,
+            _ => BitDepth::Exactly(1 << (header[7] % 5)),
+        },
+        reduce: flags & 8 != 0,
+        filter: [Filter::Default, Filter::Sub, Filter::Paeth, Filter::Adaptive][(flags >> 4 & 3) as usize],
+        compression_level: if flags & 64 != 0 { Some(header[5] % 12) } else { None },
+        strategy: if flags & 128 != 0 { Strategy::Rle } else { Strategy::Default },
+        // The parallel path only re-reads what libpng wrote, and its threads
+        // confuse leak detection.
+        threads: 0,
+    };
+    if let Ok(encoded) = png::to_vec_with_options(&mut img, &options) {
+        png::load_png_from_memory(&encoded).unwrap();
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+
+//! The header probe and the chunk parser, which read untrusted data
+//! without libpng.
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = png::dimensions(data);
+    for chunks in png::chunks::chunks(data).into_iter().chain(png::chunks::chunks_unchecked(data)) {
+        for chunk in chunks.flatten() {
+            if &chunk.chunk_type == b"eXIf" {
+                let mut exif = png::Exif::new(chunk.data.to_vec());
+                let _ = exif.orientation();
+                exif.set_orientation(png::Orientation::Normal);
+            }
+        }
+    }
+});
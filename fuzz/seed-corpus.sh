@@ -0,0 +1,10 @@
+#!/bin/sh
+# Fills fuzz/corpus/<target> with the test images and the PngSuite images.
+# The encode target takes no PNG input and starts from an empty corpus.
+set -e
+cd "$(dirname "$0")/.."
+for target in decode header is_png; do
+    mkdir -p fuzz/corpus/$target
+    cp test/*.png png-sys/libpng-1.6.16/contrib/pngsuite/*.png fuzz/corpus/$target/
+done
+mkdir -p fuzz/corpus/encode
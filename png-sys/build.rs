@@ -1,65 +1,163 @@
+extern crate cc;
+extern crate pkg_config;
+
 use std::env;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
-use std::process::Command;
-use std::process::Stdio;
+/// The oldest libpng that has everything the bindings use.
+const MIN_SYSTEM_VERSION: &str = "1.6.0";
 
 fn main() {
-    let target = env::var("TARGET").unwrap();
-    let host = env::var("HOST").unwrap();
+    if env::var_os("CARGO_FEATURE_SYSTEM_LIBPNG").is_some() {
+        link_system();
+    } else {
+        build_vendored();
+    }
+}
+
+/// Links the libpng found by pkg-config. Its symbols are not prefixed.
+fn link_system() {
+    let mut cfg = pkg_config::Config::new();
+    cfg.atleast_version(MIN_SYSTEM_VERSION);
+    // libpng.pc may belong to an older libpng installed alongside 1.6.
+    let lib = match cfg.probe("libpng16").or_else(|_| cfg.probe("libpng")) {
+        Ok(lib) => lib,
+        Err(e) => panic!("could not find libpng {} or later: {}", MIN_SYSTEM_VERSION, e),
+    };
+    println!("cargo:include={}", env::join_paths(&lib.include_paths).unwrap().to_str().unwrap());
+}
+
+/// The symbol prefix of the bundled libpng. src/ffi.rs in png links to
+/// these names.
+const PREFIX: &str = "RUST_";
+
+const SOURCES: &[&str] = &[
+    "png.c", "pngerror.c", "pngget.c", "pngmem.c", "pngpread.c", "pngread.c", "pngrio.c", "pngrtran.c",
+    "pngrutil.c", "pngset.c", "pngtrans.c", "pngwio.c", "pngwrite.c", "pngwtran.c", "pngwutil.c",
+];
 
-    if host != target {
-        if env::var_os("CC").is_none() {
-            env::set_var("CC", format!("{}-gcc", target));
+/// Builds the bundled libpng with the `RUST_` symbol prefix.
+fn build_vendored() {
+    let src = PathBuf::from(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("libpng-1.6.16");
+    let dst = PathBuf::from(&env::var("OUT_DIR").unwrap());
+
+    let mut cfg = cc::Build::new();
+    for file in SOURCES {
+        cfg.file(src.join(file));
+    }
+    cfg.include(&dst).include(&src).warnings(false);
+    if let Some(include) = env::var_os("DEP_Z_INCLUDE") {
+        cfg.include(include);
+    }
+    let filter_optimizations = add_filter_optimizations(&mut cfg, &src);
+
+    write_pnglibconf(&src, &dst);
+    write_pngprefix(&src, &dst, filter_optimizations);
+    // The cc crate passes CFLAGS on, e.g. for sanitizers.
+    cfg.compile("libpng16.a");
+
+    println!("cargo:rerun-if-changed={}", src.display());
+    println!("cargo:rerun-if-changed=intel");
+    println!("cargo:root={}", dst.display());
+    println!("cargo:include={}", env::join_paths([&dst, &src]).unwrap().to_str().unwrap());
+    println!("cargo:prefix={}", PREFIX);
+}
+
+/// Adds the SIMD row unfilters enabled by the `neon` and `sse` features for
+/// the target, and returns the name of the function installing them.
+fn add_filter_optimizations(cfg: &mut cc::Build, src: &Path) -> Option<&'static str> {
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let neon = env::var_os("CARGO_FEATURE_NEON").is_some();
+    let sse = env::var_os("CARGO_FEATURE_SSE").is_some();
+    match &arch[..] {
+        "aarch64" if neon => {
+            // NEON is part of the architecture.
+            cfg.file(src.join("arm/arm_init.c")).file(src.join("arm/filter_neon_intrinsics.c"));
+            cfg.define("PNG_ARM_NEON_OPT", Some("2"));
+            Some("png_init_filter_functions_neon")
         }
-        if env::var_os("AR").is_none() {
-            env::set_var("AR", format!("{}-ar", target));
+        "arm" if neon => {
+            // Without -mfpu=neon pngpriv.h picks the assembler version,
+            // which libpng only uses if /proc/cpuinfo lists NEON.
+            if env::var("CARGO_CFG_TARGET_OS").unwrap() != "linux" {
+                panic!("the neon feature needs Linux on 32-bit ARM, to check for NEON at run time");
+            }
+            cfg.file(src.join("arm/arm_init.c"))
+               .file(src.join("arm/filter_neon_intrinsics.c"))
+               .file(src.join("arm/filter_neon.S"));
+            cfg.define("PNG_ARM_NEON_OPT", Some("1")).define("PNG_ARM_NEON_CHECK_SUPPORTED", None);
+            Some("png_init_filter_functions_neon")
         }
-        if env::var_os("RANLIB").is_none() {
-            env::set_var("RANLIB", format!("{}-ranlib", target));
+        "x86_64" if sse => {
+            // SSE2 is part of the architecture, SSSE3 is checked at run time.
+            cfg.file(src.join("../intel/filter_sse.c"));
+            cfg.define("PNG_ARM_NEON_OPT", Some("0"))
+               .define("PNG_FILTER_OPTIMIZATIONS", Some("png_init_filter_functions_sse"));
+            Some("png_init_filter_functions_sse")
+        }
+        _ => {
+            cfg.define("PNG_ARM_NEON_OPT", Some("0"));
+            None
         }
     }
+}
 
-    let cfg = PathBuf::from(&env::var("CARGO_MANIFEST_DIR")
-                            .unwrap()).join("libpng-1.6.16/configure");
-    let dst = PathBuf::from(&env::var("OUT_DIR").unwrap());
-    env::set_var("CFLAGS", "-fPIC -O3");
-    let mut cmd;
-    if host.find("windows").is_some() {
-        // Here be windows hacks
-
-        // Windows doesn't know how to handle executables
-        // with shebangs, so we pass it through `sh`
-        cmd = Command::new(PathBuf::from("sh"));
+/// Writes the prebuilt configuration with the public API renamed, as
+/// configure does with `--with-libpng-prefix`.
+fn write_pnglibconf(src: &Path, dst: &Path) {
+    let prebuilt = read(&src.join("scripts/pnglibconf.h.prebuilt"));
+    let end = prebuilt.rfind("/* end of settings */").unwrap();
+    let mut out = String::from(&prebuilt[..end]);
+    // Follow the zlib libpng is compiled against.
+    out = out.replace("#define PNG_ZLIB_VERNUM 0 /* unknown */", "#define PNG_ZLIB_VERNUM ZLIB_VERNUM");
+    out.push_str(&format!("#define PNG_PREFIX {}\n", PREFIX));
+    for line in read(&src.join("scripts/symbols.def")).lines() {
+        // " png_name @ordinal"
+        if let Some(name) = line.split_whitespace().next().filter(|name| name.starts_with("png_")) {
+            out.push_str(&format!("#define {} {}{}\n", name, PREFIX, name));
+        }
+    }
+    // png.h maps these to inline macros, under the prefixed names.
+    for line in read(&src.join("scripts/macro.lst")).lines() {
+        out.push_str(&format!("#define {}png_{} PNG_{}\n", PREFIX, line, line));
+    }
+    out.push_str(&prebuilt[end..]);
+    write(&dst.join("pnglibconf.h"), &out);
+}
 
-        // Windows under mingw also doesn't understand its
-        // own paths, so we pass the path through cygpath
-        cmd.arg("-c").arg(&format!("$(cygpath \"{}\") --with-libpng-prefix=RUST_",
-                                   cfg.to_str().unwrap()));
-    } else {
-        cmd = Command::new(cfg);
-        cmd.arg("--with-libpng-prefix=RUST_");
-        if host != target {
-            cmd.arg(format!("--host={}", target));
+/// Writes the header renaming libpng's internal functions and data, which
+/// pngpriv.h includes when `PNG_PREFIX` is set. pngpriv.h declares the
+/// filter optimization function through a macro, so it is named separately.
+fn write_pngprefix(src: &Path, dst: &Path, filter_optimizations: Option<&str>) {
+    let pngpriv = read(&src.join("pngpriv.h"));
+    let mut out = String::new();
+    for kind in &["PNG_INTERNAL_FUNCTION(", "PNG_INTERNAL_DATA(", "PNG_INTERNAL_CALLBACK("] {
+        for (start, _) in pngpriv.match_indices(kind) {
+            // The name is the second macro argument. Names not starting
+            // with png_ are macros naming other functions.
+            let args = &pngpriv[start + kind.len()..];
+            let name = args.split(',').nth(1).unwrap().trim();
+            if name.starts_with("png_") && name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric()) {
+                out.push_str(&format!("#define {} {}{}\n", name, PREFIX, name));
+            }
         }
     }
-    cmd.current_dir(&dst);
-    run(&mut cmd);
-    let mut cmd = Command::new("make");
-    cmd.arg("-j4");
-    cmd.current_dir(&dst);
-    run(&mut cmd);
+    if let Some(name) = filter_optimizations {
+        out.push_str(&format!("#define {} {}{}\n", name, PREFIX, name));
+    }
+    write(&dst.join("pngprefix.h"), &out);
+}
 
-    println!("cargo:root={}", dst.display());
-    println!("cargo:rustc-link-search=native={}/.libs", dst.display());
-    println!("cargo:rustc-link-lib=static=png16");
+fn read(path: &Path) -> String {
+    let mut contents = String::new();
+    File::open(path).and_then(|mut file| file.read_to_string(&mut contents))
+                    .unwrap_or_else(|e| panic!("could not read {}: {}", path.display(), e));
+    contents
 }
 
-fn run(cmd: &mut Command) {
-    println!("running: {:?}", cmd);
-    assert!(cmd.stdout(Stdio::inherit())
-               .stderr(Stdio::inherit())
-               .status()
-               .unwrap()
-               .success());
+fn write(path: &Path, contents: &str) {
+    File::create(path).and_then(|mut file| file.write_all(contents.as_bytes()))
+                      .unwrap_or_else(|e| panic!("could not write {}: {}", path.display(), e));
 }
@@ -0,0 +1,206 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/* SSE2 versions of libpng's Sub, Avg and Paeth unfilters for 3 and 4 byte
+ * pixels, installed through PNG_FILTER_OPTIMIZATIONS. Each pixel depends on
+ * the one to its left, so a whole pixel is handled at a time. Paeth uses
+ * SSSE3 when the CPU has it. */
+
+#include <string.h>
+#include <emmintrin.h>
+#include <tmmintrin.h>
+
+#include "pngpriv.h"
+
+#ifdef PNG_READ_SUPPORTED
+
+#if defined(__GNUC__)
+#  define TARGET_SSSE3 __attribute__((target("ssse3")))
+static int have_ssse3(void) {
+  __builtin_cpu_init();
+  return __builtin_cpu_supports("ssse3");
+}
+#elif defined(_MSC_VER)
+#  include <intrin.h>
+#  define TARGET_SSSE3
+static int have_ssse3(void) {
+  int info[4];
+  __cpuid(info, 1);
+  return (info[2] >> 9) & 1;
+}
+#else
+#  define TARGET_SSSE3
+static int have_ssse3(void) {
+  return 0;
+}
+#endif
+
+/* Rows are not aligned and may end right after the last pixel, so pixels
+ * are moved with memcpy. 3-byte pixels are loaded as 4 bytes except at the
+ * end of the row. The extra byte stays in its own lane and is not stored. */
+
+static __m128i load3(const void *p) {
+  int v = 0;
+  memcpy(&v, p, 3);
+  return _mm_cvtsi32_si128(v);
+}
+
+static void store3(void *p, __m128i v) {
+  int u = _mm_cvtsi128_si32(v);
+  memcpy(p, &u, 3);
+}
+
+static __m128i load4(const void *p) {
+  int v;
+  memcpy(&v, p, 4);
+  return _mm_cvtsi32_si128(v);
+}
+
+static void store4(void *p, __m128i v) {
+  int u = _mm_cvtsi128_si32(v);
+  memcpy(p, &u, 4);
+}
+
+/* Sub adds up the pixels of a row, so several pixels are done at once by
+ * adding shifted copies of a vector to itself, as in a prefix sum. */
+
+static void sub3(png_row_infop row_info, png_bytep row, png_const_bytep prev_row) {
+  png_bytep end = row + row_info->rowbytes;
+  __m128i low3 = _mm_cvtsi32_si128(0xffffff);
+  __m128i a = _mm_setzero_si128();
+  PNG_UNUSED(prev_row)
+  /* Four pixels in the low 12 bytes of a 16-byte load. */
+  for (; end - row >= 16; row += 12) {
+    __m128i d = _mm_loadu_si128((const __m128i *)row);
+    d = _mm_add_epi8(d, _mm_slli_si128(d, 3));
+    d = _mm_add_epi8(d, _mm_slli_si128(d, 6));
+    a = _mm_add_epi8(a, _mm_slli_si128(a, 3));
+    a = _mm_add_epi8(a, _mm_slli_si128(a, 6));
+    d = _mm_add_epi8(d, a);
+    _mm_storel_epi64((__m128i *)row, d);
+    store4(row + 8, _mm_srli_si128(d, 8));
+    a = _mm_and_si128(_mm_srli_si128(d, 9), low3);
+  }
+  for (; row < end; row += 3) {
+    a = _mm_add_epi8(a, end - row >= 4 ? load4(row) : load3(row));
+    store3(row, a);
+  }
+}
+
+static void sub4(png_row_infop row_info, png_bytep row, png_const_bytep prev_row) {
+  png_bytep end = row + row_info->rowbytes;
+  __m128i a = _mm_setzero_si128();
+  PNG_UNUSED(prev_row)
+  /* a holds the last pixel in every lane. */
+  for (; end - row >= 16; row += 16) {
+    __m128i d = _mm_loadu_si128((const __m128i *)row);
+    d = _mm_add_epi8(d, _mm_slli_si128(d, 4));
+    d = _mm_add_epi8(d, _mm_slli_si128(d, 8));
+    d = _mm_add_epi8(d, a);
+    _mm_storeu_si128((__m128i *)row, d);
+    a = _mm_shuffle_epi32(d, 0xff);
+  }
+  for (; row < end; row += 4) {
+    a = _mm_add_epi8(a, load4(row));
+    store4(row, a);
+  }
+}
+
+/* (a + b) / 2 rounded down, where _mm_avg_epu8 rounds up. */
+static __m128i average(__m128i a, __m128i b) {
+  __m128i odd = _mm_and_si128(_mm_xor_si128(a, b), _mm_set1_epi8(1));
+  return _mm_sub_epi8(_mm_avg_epu8(a, b), odd);
+}
+
+static void avg3(png_row_infop row_info, png_bytep row, png_const_bytep prev_row) {
+  png_bytep end = row + row_info->rowbytes;
+  __m128i a = _mm_setzero_si128();
+  for (; row < end; row += 3, prev_row += 3) {
+    __m128i b = end - row >= 4 ? load4(prev_row) : load3(prev_row);
+    a = _mm_add_epi8(average(a, b), end - row >= 4 ? load4(row) : load3(row));
+    store3(row, a);
+  }
+}
+
+static void avg4(png_row_infop row_info, png_bytep row, png_const_bytep prev_row) {
+  png_bytep end = row + row_info->rowbytes;
+  __m128i a = _mm_setzero_si128();
+  for (; row < end; row += 4, prev_row += 4) {
+    a = _mm_add_epi8(average(a, load4(prev_row)), load4(row));
+    store4(row, a);
+  }
+}
+
+static __m128i abs_sse2(__m128i x) {
+  return _mm_max_epi16(x, _mm_sub_epi16(_mm_setzero_si128(), x));
+}
+
+static TARGET_SSSE3 __m128i abs_ssse3(__m128i x) {
+  return _mm_abs_epi16(x);
+}
+
+static __m128i blend(__m128i mask, __m128i a, __m128i b) {
+  return _mm_or_si128(_mm_and_si128(mask, a), _mm_andnot_si128(mask, b));
+}
+
+/* Sets p to the Paeth predictor of 16-bit samples a (left), b (up) and c
+ * (up-left), breaking ties like libpng: a, then b, then c. */
+#define PAETH(abs, p, a, b, c) do { \
+  __m128i pa = abs(_mm_sub_epi16(b, c)); \
+  __m128i pb = abs(_mm_sub_epi16(a, c)); \
+  __m128i pc = abs(_mm_add_epi16(_mm_sub_epi16(b, c), _mm_sub_epi16(a, c))); \
+  __m128i smallest = _mm_min_epi16(_mm_min_epi16(pa, pb), pc); \
+  p = blend(_mm_cmpeq_epi16(pa, smallest), a, blend(_mm_cmpeq_epi16(pb, smallest), b, c)); \
+} while (0)
+
+/* Defines Paeth unfilters for both pixel sizes, taking the absolute value
+ * of 16-bit lanes with abs. The left and up-left pixels start as zero. */
+#define PAETH_FILTERS(suffix, target, abs) \
+  static target void paeth3_##suffix(png_row_infop row_info, png_bytep row, png_const_bytep prev_row) { \
+    png_bytep end = row + row_info->rowbytes; \
+    __m128i zero = _mm_setzero_si128(); \
+    __m128i a = zero, c = zero; \
+    for (; row < end; row += 3, prev_row += 3) { \
+      __m128i b = _mm_unpacklo_epi8(end - row >= 4 ? load4(prev_row) : load3(prev_row), zero); \
+      __m128i p, x; \
+      PAETH(abs, p, a, b, c); \
+      x = _mm_add_epi8(_mm_packus_epi16(p, p), end - row >= 4 ? load4(row) : load3(row)); \
+      store3(row, x); \
+      a = _mm_unpacklo_epi8(x, zero); \
+      c = b; \
+    } \
+  } \
+  static target void paeth4_##suffix(png_row_infop row_info, png_bytep row, png_const_bytep prev_row) { \
+    png_bytep end = row + row_info->rowbytes; \
+    __m128i zero = _mm_setzero_si128(); \
+    __m128i a = zero, c = zero; \
+    for (; row < end; row += 4, prev_row += 4) { \
+      __m128i b = _mm_unpacklo_epi8(load4(prev_row), zero); \
+      __m128i p, x; \
+      PAETH(abs, p, a, b, c); \
+      x = _mm_add_epi8(_mm_packus_epi16(p, p), load4(row)); \
+      store4(row, x); \
+      a = _mm_unpacklo_epi8(x, zero); \
+      c = b; \
+    } \
+  }
+
+PAETH_FILTERS(sse2, , abs_sse2)
+PAETH_FILTERS(ssse3, TARGET_SSSE3, abs_ssse3)
+
+void png_init_filter_functions_sse(png_structp pp, unsigned int bpp) {
+  int ssse3 = have_ssse3();
+
+  if (bpp == 3) {
+    pp->read_filter[PNG_FILTER_VALUE_SUB-1] = sub3;
+    pp->read_filter[PNG_FILTER_VALUE_AVG-1] = avg3;
+    pp->read_filter[PNG_FILTER_VALUE_PAETH-1] = ssse3 ? paeth3_ssse3 : paeth3_sse2;
+  } else if (bpp == 4) {
+    pp->read_filter[PNG_FILTER_VALUE_SUB-1] = sub4;
+    pp->read_filter[PNG_FILTER_VALUE_AVG-1] = avg4;
+    pp->read_filter[PNG_FILTER_VALUE_PAETH-1] = ssse3 ? paeth4_ssse3 : paeth4_sse2;
+  }
+}
+
+#endif /* PNG_READ_SUPPORTED */
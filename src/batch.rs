@@ -0,0 +1,201 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Decoding of many files on a pool of threads.
+//!
+//! Every decode uses its own libpng read struct, so decodes on different
+//! threads share no state.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::vec;
+
+use super::{load_png_from_memory_with_options, DecodeOptions, Image};
+
+/// Settings for `decode_batch`.
+#[derive(Clone, Default)]
+pub struct BatchOptions {
+    pub decode: DecodeOptions,
+    /// The most memory, in bytes, that files being decoded and decoded
+    /// images not yet returned by the iterator may take up. The size of an
+    /// image is estimated from its header before it is read. An image
+    /// larger than the limit is still decoded once nothing else is held.
+    pub memory_limit: Option<usize>,
+}
+
+/// Tracks the memory reserved by the workers.
+struct Budget {
+    limit: Option<usize>,
+    /// The bytes in use and whether the batch has been dropped.
+    state: Mutex<(usize, bool)>,
+    freed: Condvar,
+}
+
+impl Budget {
+    /// Waits until `size` bytes are available and reserves them. Returns
+    /// false if the batch was dropped in the meantime.
+    fn acquire(&self, size: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let (used, cancelled) = *state;
+            if cancelled {
+                return false;
+            }
+            match self.limit {
+                Some(limit) if used != 0 && used + size > limit => {}
+                _ => {
+                    state.0 += size;
+                    return true;
+                }
+            }
+            state = self.freed.wait(state).unwrap();
+        }
+    }
+
+    fn release(&self, size: usize) {
+        self.state.lock().unwrap().0 -= size;
+        self.freed.notify_all();
+    }
+
+    fn cancel(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.freed.notify_all();
+    }
+}
+
+/// The decoded images of a batch, in the order they finish.
+pub struct DecodeBatch {
+    results: Receiver<(PathBuf, Result<Image, String>, usize)>,
+    budget: Arc<Budget>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Iterator for DecodeBatch {
+    type Item = (PathBuf, Result<Image, String>);
+
+    fn next(&mut self) -> Option<(PathBuf, Result<Image, String>)> {
+        let (path, result, size) = self.results.recv().ok()?;
+        // The caller owns the image from here on.
+        self.budget.release(size);
+        Some((path, result))
+    }
+}
+
+impl Drop for DecodeBatch {
+    fn drop(&mut self) {
+        self.budget.cancel();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Decodes the files at `paths` on `threads` threads, or one per available
+/// core if `threads` is 0. Dropping the iterator early stops the workers
+/// after their current file.
+pub fn decode_batch<I>(paths: I, threads: usize, options: &BatchOptions) -> DecodeBatch
+    where I: IntoIterator, I::Item: Into<PathBuf> {
+    let paths: Vec<PathBuf> = paths.into_iter().map(|path| path.into()).collect();
+    let threads = match threads {
+        0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        n => n,
+    };
+    let budget = Arc::new(Budget {
+        limit: options.memory_limit,
+        state: Mutex::new((0, false)),
+        freed: Condvar::new(),
+    });
+    let (sender, results) = channel();
+    let queue: Arc<Mutex<vec::IntoIter<PathBuf>>> = Arc::new(Mutex::new(paths.into_iter()));
+
+    let workers = (0..threads).map(|_| {
+        let (queue, budget, sender) = (queue.clone(), budget.clone(), sender.clone());
+        let options = options.decode.clone();
+        thread::spawn(move || loop {
+            let path = match queue.lock().unwrap().next() {
+                Some(path) => path,
+                None => break,
+            };
+            let size = estimate_size(&path, &options);
+            if !budget.acquire(size) {
+                break;
+            }
+            let result = read_file(&path).and_then(|data| load_png_from_memory_with_options(&data, &options));
+            if sender.send((path, result, size)).is_err() {
+                break;
+            }
+        })
+    }).collect();
+
+    DecodeBatch { results, budget, workers }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    let mut data = vec![];
+    File::open(path).and_then(|mut file| file.read_to_end(&mut data))
+                    .map_err(|e| format!("could not read file: {}", e))?;
+    Ok(data)
+}
+
+/// Estimates the memory needed to decode the file at `path` from its size
+/// and the dimensions in its header.
+fn estimate_size(path: &Path, options: &DecodeOptions) -> usize {
+    let mut header = [0; 33];
+    let file_len = match File::open(path).and_then(|mut file| {
+        file.read_exact(&mut header)?;
+        file.metadata()
+    }) {
+        Ok(metadata) => metadata.len() as usize,
+        // Decoding will fail quickly.
+        Err(_) => return 0,
+    };
+    let width = u32::from_be_bytes([header[16], header[17], header[18], header[19]]) as usize;
+    let height = u32::from_be_bytes([header[20], header[21], header[22], header[23]]) as usize;
+    let bytes_per_sample = if header[24] == 16 && options.keep_16_bit { 2 } else { 1 };
+    file_len.saturating_add(width.saturating_mul(height).saturating_mul(4 * bytes_per_sample))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::{decode_batch, BatchOptions};
+    use super::super::{load_png, Image};
+
+    #[test]
+    fn test_image_is_send() {
+        fn assert_send<T: Send + Sync>() {}
+        assert_send::<Image>();
+    }
+
+    #[test]
+    fn test_decode_batch() {
+        let files = ["test/gray.png", "test/servo-screenshot.png", "test/missing.png",
+                     "test/mozilla-dinosaur-head-logo.png", "test/rust-huge-logo.png"];
+        // Too small for any image, so they are decoded one at a time.
+        let options = BatchOptions { memory_limit: Some(1), ..Default::default() };
+        let mut results: Vec<(PathBuf, Result<Image, String>)> = decode_batch(files.iter(), 3, &options).collect();
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(results.len(), files.len());
+        for (path, result) in results {
+            match load_png(&path) {
+                Ok(expected) => assert!(result.unwrap() == expected),
+                Err(_) => assert!(result.is_err()),
+            }
+        }
+
+        // Dropping the iterator early must not hang.
+        let mut batch = decode_batch(files.iter().cycle().take(20), 2, &Default::default());
+        assert!(batch.next().is_some());
+    }
+}
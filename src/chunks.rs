@@ -0,0 +1,247 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Chunk-level access to PNG streams, independent of libpng.
+//!
+//! This allows listing, reordering, stripping and adding chunks without
+//! decoding or re-encoding the image data.
+
+use std::io::Write;
+
+use super::is_png;
+
+/// The eight byte signature every PNG stream starts with.
+pub const SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// A single chunk borrowed from a PNG stream.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Chunk<'a> {
+    pub chunk_type: [u8; 4],
+    pub data: &'a [u8],
+    pub crc: u32,
+}
+
+impl<'a> Chunk<'a> {
+    /// Critical chunks (`IHDR`, `PLTE`, `IDAT`, `IEND`) are needed to display the image.
+    pub fn is_critical(&self) -> bool {
+        self.chunk_type[0] & 0x20 == 0
+    }
+
+    /// Whether an editor that does not understand this chunk may copy it
+    /// to a modified stream.
+    pub fn is_safe_to_copy(&self) -> bool {
+        self.chunk_type[3] & 0x20 != 0
+    }
+
+    /// Checks the stored CRC against the one computed over type and data.
+    pub fn crc_is_valid(&self) -> bool {
+        crc32(&self.chunk_type, self.data) == self.crc
+    }
+}
+
+/// Iterator over the chunks of a PNG stream, yielding errors for truncated
+/// or corrupt chunks. Iteration stops after `IEND` or the first error.
+pub struct Chunks<'a> {
+    data: &'a [u8],
+    offset: usize,
+    verify_crc: bool,
+    done: bool,
+}
+
+/// Returns an iterator over the chunks following the PNG signature.
+/// CRCs are verified; use `chunks_unchecked` to skip the check.
+pub fn chunks(png: &[u8]) -> Result<Chunks<'_>, String> {
+    let mut chunks = chunks_from(png)?;
+    chunks.verify_crc = true;
+    Ok(chunks)
+}
+
+/// Like `chunks`, but does not verify the CRC of each chunk.
+pub fn chunks_unchecked(png: &[u8]) -> Result<Chunks<'_>, String> {
+    chunks_from(png)
+}
+
+fn chunks_from(png: &[u8]) -> Result<Chunks<'_>, String> {
+    if !is_png(png) {
+        return Err("not a png".to_string());
+    }
+    Ok(Chunks {
+        data: png,
+        offset: SIGNATURE.len(),
+        verify_crc: false,
+        done: false,
+    })
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<Chunk<'a>, String>;
+
+    fn next(&mut self) -> Option<Result<Chunk<'a>, String>> {
+        if self.done || self.offset == self.data.len() {
+            return None;
+        }
+        let chunk = self.read_chunk();
+        match chunk {
+            Ok(ref chunk) if &chunk.chunk_type != b"IEND" => {}
+            _ => self.done = true,
+        }
+        Some(chunk)
+    }
+}
+
+impl<'a> Chunks<'a> {
+    fn read_chunk(&mut self) -> Result<Chunk<'a>, String> {
+        let rest = &self.data[self.offset..];
+        if rest.len() < 12 {
+            return Err(format!("truncated chunk at offset {}", self.offset));
+        }
+        let length = read_u32(&rest[0..4]) as usize;
+        if length > 0x7fffffff || rest.len() - 12 < length {
+            return Err(format!("truncated chunk at offset {}", self.offset));
+        }
+        let mut chunk_type = [0; 4];
+        chunk_type.copy_from_slice(&rest[4..8]);
+        if !chunk_type.iter().all(|c| c.is_ascii_alphabetic()) {
+            return Err(format!("invalid chunk type at offset {}", self.offset));
+        }
+        let chunk = Chunk {
+            chunk_type,
+            data: &rest[8..8 + length],
+            crc: read_u32(&rest[8 + length..12 + length]),
+        };
+        if self.verify_crc && !chunk.crc_is_valid() {
+            return Err(format!("bad crc in {} chunk at offset {}",
+                               String::from_utf8_lossy(&chunk_type), self.offset));
+        }
+        self.offset += 12 + length;
+        Ok(chunk)
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+/// The CRC-32 of each byte value, built once at compile time.
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+/// Computes the CRC-32 stored after each chunk, which covers the chunk
+/// type and data but not the length.
+pub fn crc32(chunk_type: &[u8; 4], data: &[u8]) -> u32 {
+    let mut c = 0xffffffffu32;
+    for &byte in chunk_type.iter().chain(data.iter()) {
+        c = CRC_TABLE[((c ^ byte as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    c ^ 0xffffffff
+}
+
+/// Writes a PNG stream chunk by chunk, filling in lengths and CRCs.
+pub struct ChunkWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    /// Creates a writer and emits the PNG signature.
+    pub fn new(mut writer: W) -> Result<ChunkWriter<W>, String> {
+        match writer.write_all(&SIGNATURE) {
+            Ok(()) => Ok(ChunkWriter { writer }),
+            Err(e) => Err(format!("could not write signature: {}", e)),
+        }
+    }
+
+    pub fn write_chunk(&mut self, chunk_type: &[u8; 4], data: &[u8]) -> Result<(), String> {
+        if data.len() > 0x7fffffff {
+            return Err("chunk too large".to_string());
+        }
+        let length = data.len() as u32;
+        let crc = crc32(chunk_type, data);
+        let result = self.writer.write_all(&length.to_be_bytes())
+            .and_then(|_| self.writer.write_all(chunk_type))
+            .and_then(|_| self.writer.write_all(data))
+            .and_then(|_| self.writer.write_all(&crc.to_be_bytes()));
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("could not write chunk: {}", e)),
+        }
+    }
+
+    /// Copies a chunk read from another stream, recomputing its CRC.
+    pub fn copy_chunk(&mut self, chunk: &Chunk) -> Result<(), String> {
+        self.write_chunk(&chunk.chunk_type, chunk.data)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Read;
+
+    use super::{chunks, crc32, ChunkWriter};
+
+    fn read_file(path: &str) -> Vec<u8> {
+        let mut buf = vec![];
+        File::open(path).unwrap().read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_crc32() {
+        // CRC of an empty IEND chunk, as found at the end of every PNG.
+        assert_eq!(crc32(b"IEND", &[]), 0xae426082);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let png = read_file("test/servo-screenshot.png");
+        let types: Vec<[u8; 4]> = chunks(&png).unwrap().map(|c| c.unwrap().chunk_type).collect();
+        assert_eq!(&types[0], b"IHDR");
+        assert_eq!(&types[types.len() - 1], b"IEND");
+        assert!(types.iter().any(|t| t == b"IDAT"));
+    }
+
+    #[test]
+    fn test_chunk_writer_round_trip() {
+        let png = read_file("test/gray.png");
+        let mut writer = ChunkWriter::new(vec![]).unwrap();
+        for chunk in chunks(&png).unwrap() {
+            writer.copy_chunk(&chunk.unwrap()).unwrap();
+        }
+        assert_eq!(writer.into_inner(), png);
+    }
+
+    #[test]
+    fn test_bad_crc() {
+        let mut png = read_file("test/gray.png");
+        // Corrupt the CRC of IHDR, which directly follows the signature.
+        png[8 + 8 + 13] ^= 0xff;
+        let first = chunks(&png).unwrap().next().unwrap();
+        assert!(first.is_err());
+        assert!(chunks(b"GIF89a").is_err());
+    }
+}
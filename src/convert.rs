@@ -0,0 +1,346 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Conversion of decoded images between pixel formats.
+//!
+//! Every pixel is widened to 16-bit RGBA, composited onto the background if
+//! the target has no alpha, reduced to gray if the target has no color and
+//! rounded to the target depth. Samples are treated as they are stored,
+//! without gamma correction. Targets are `PixelFormat`s, so packed gray and
+//! palette layouts are only ever sources.
+
+use std::cmp;
+
+use libc::c_int;
+
+use super::{ffi, pack, Background, GrayWeights, Image, Metadata, PaletteEntry, PixelFormat};
+use super::{PixelsByColorType, SignificantBits};
+
+/// Settings for `Image::convert_with_options`.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct ConvertOptions {
+    /// Weights for converting color to gray.
+    pub to_gray: GrayWeights,
+    /// The color transparent pixels are composited onto when the target has
+    /// no alpha channel. Black by default.
+    pub background: [u8; 3],
+}
+
+/// The red, green and blue weights out of `ffi::FP_1`.
+type Weights = (u64, u64, u64);
+
+pub fn convert(img: &Image, target: PixelFormat, options: &ConvertOptions) -> Result<Image, String> {
+    let gray_weights = options.to_gray.to_fixed()?;
+    let weights = weights(gray_weights);
+    let (depth, _, channels) = img.pixels.layout();
+    let row_bytes = (img.width as usize).checked_mul(channels * depth as usize).map(|bits| bits.div_ceil(8));
+    if row_bytes.and_then(|row_bytes| row_bytes.checked_mul(img.height as usize)) != Some(img.pixels.byte_len()) {
+        return Err("pixel data does not match the image size".to_string());
+    }
+    let pixels = match rgba16(img) {
+        Some(pixels) => pixels,
+        None => return Err("palette index out of range".to_string()),
+    };
+
+    let background = options.background.map(|sample| sample as u16 * 257);
+    let mut samples = Vec::with_capacity(pixels.len() * target.channels());
+    for &pixel in &pixels {
+        let [r, g, b, a] = if target.has_alpha() { pixel } else { composite(pixel, background) };
+        let k = if target.is_color() { 0 } else { gray([r, g, b], weights) };
+        match target {
+            PixelFormat::K8 | PixelFormat::K16 => samples.push(k),
+            PixelFormat::KA8 | PixelFormat::KA16 => samples.extend_from_slice(&[k, a]),
+            PixelFormat::RGB8 | PixelFormat::RGB16 => samples.extend_from_slice(&[r, g, b]),
+            PixelFormat::RGBA8 | PixelFormat::RGBA16 => samples.extend_from_slice(&[r, g, b, a]),
+            PixelFormat::BGR8 => samples.extend_from_slice(&[b, g, r]),
+            PixelFormat::BGRA8 => samples.extend_from_slice(&[b, g, r, a]),
+            PixelFormat::ARGB8 => samples.extend_from_slice(&[a, r, g, b]),
+        }
+    }
+    let bytes = || samples.iter().map(|&sample| narrow(sample)).collect();
+    let pixels = match target {
+        PixelFormat::K8 => PixelsByColorType::K8(bytes()),
+        PixelFormat::KA8 => PixelsByColorType::KA8(bytes()),
+        PixelFormat::RGB8 => PixelsByColorType::RGB8(bytes()),
+        PixelFormat::RGBA8 => PixelsByColorType::RGBA8(bytes()),
+        PixelFormat::BGR8 => PixelsByColorType::BGR8(bytes()),
+        PixelFormat::BGRA8 => PixelsByColorType::BGRA8(bytes()),
+        PixelFormat::ARGB8 => PixelsByColorType::ARGB8(bytes()),
+        PixelFormat::K16 => PixelsByColorType::K16(samples),
+        PixelFormat::KA16 => PixelsByColorType::KA16(samples),
+        PixelFormat::RGB16 => PixelsByColorType::RGB16(samples),
+        PixelFormat::RGBA16 => PixelsByColorType::RGBA16(samples),
+    };
+
+    let metadata = &img.metadata;
+    let (depth, color_type, _) = img.pixels.layout();
+    let palette = img.pixels.palette();
+    let background = metadata.background.and_then(|color| {
+        convert_background(color, depth, palette, target, gray_weights, narrow)
+    });
+    // Palette entries always have 8-bit samples and may be transparent.
+    let (depth, has_alpha) = match palette {
+        Some(_) => (8, true),
+        None => (depth, color_type & ffi::COLOR_MASK_ALPHA != 0),
+    };
+    let is_color = color_type & ffi::COLOR_MASK_COLOR != 0;
+    Ok(Image {
+        width: img.width,
+        height: img.height,
+        pixels,
+        metadata: Metadata {
+            background,
+            significant_bits: metadata.significant_bits.map(|bits| {
+                convert_significant_bits(bits, depth as u8, is_color, has_alpha, target)
+            }),
+            ..metadata.clone()
+        },
+    })
+}
+
+fn weights((red, green): (ffi::png_fixed_point, ffi::png_fixed_point)) -> Weights {
+    (red as u64, green as u64, (ffi::FP_1 - red - green) as u64)
+}
+
+/// Widens a sample of `depth` bits to 16 bits.
+fn widen(sample: u16, depth: c_int) -> u16 {
+    match depth {
+        16 => sample,
+        8 => sample * 257,
+        depth => sample * pack::gray_step(depth as u8) as u16 * 257,
+    }
+}
+
+/// Rounds a 16-bit sample to 8 bits.
+fn narrow(sample: u16) -> u8 {
+    ((sample as u32 * 255 + 32767) / 65535) as u8
+}
+
+fn gray([r, g, b]: [u16; 3], (wr, wg, wb): Weights) -> u16 {
+    let fp_1 = ffi::FP_1 as u64;
+    ((r as u64 * wr + g as u64 * wg + b as u64 * wb + fp_1 / 2) / fp_1) as u16
+}
+
+fn composite([r, g, b, a]: [u16; 4], background: [u16; 3]) -> [u16; 4] {
+    let blend = |color: u16, background: u16| {
+        ((color as u32 * a as u32 + background as u32 * (65535 - a as u32) + 32767) / 65535) as u16
+    };
+    [blend(r, background[0]), blend(g, background[1]), blend(b, background[2]), 65535]
+}
+
+fn entry_rgba(entry: &PaletteEntry) -> [u16; 4] {
+    [entry.red, entry.green, entry.blue, entry.alpha].map(|sample| sample as u16 * 257)
+}
+
+/// Returns the pixels as 16-bit RGBA, or `None` if a palette index is out
+/// of range. The sizes must have been checked.
+fn rgba16(img: &Image) -> Option<Vec<[u16; 4]>> {
+    let (depth, _, _) = img.pixels.layout();
+    let gray = |k: u16| [k, k, k, 65535];
+    let wide8 = |p: &[u8]| -> Vec<u16> { p.iter().map(|&sample| widen(sample as u16, 8)).collect() };
+    Some(match img.pixels {
+        PixelsByColorType::K8(ref p) => wide8(p).into_iter().map(gray).collect(),
+        PixelsByColorType::KA8(ref p) => wide8(p).chunks(2).map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        PixelsByColorType::RGB8(ref p) => wide8(p).chunks(3).map(|p| [p[0], p[1], p[2], 65535]).collect(),
+        PixelsByColorType::RGBA8(ref p) => wide8(p).chunks(4).map(|p| [p[0], p[1], p[2], p[3]]).collect(),
+        PixelsByColorType::BGR8(ref p) => wide8(p).chunks(3).map(|p| [p[2], p[1], p[0], 65535]).collect(),
+        PixelsByColorType::BGRA8(ref p) => wide8(p).chunks(4).map(|p| [p[2], p[1], p[0], p[3]]).collect(),
+        PixelsByColorType::ARGB8(ref p) => wide8(p).chunks(4).map(|p| [p[1], p[2], p[3], p[0]]).collect(),
+        PixelsByColorType::K16(ref p) => p.iter().cloned().map(gray).collect(),
+        PixelsByColorType::KA16(ref p) => p.chunks(2).map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        PixelsByColorType::RGB16(ref p) => p.chunks(3).map(|p| [p[0], p[1], p[2], 65535]).collect(),
+        PixelsByColorType::RGBA16(ref p) => p.chunks(4).map(|p| [p[0], p[1], p[2], p[3]]).collect(),
+        PixelsByColorType::K1(_) | PixelsByColorType::K2(_) | PixelsByColorType::K4(_) => {
+            let samples = img.pixels.unpacked_samples(img.width, img.height).unwrap();
+            samples.iter().map(|&sample| gray(widen(sample as u16, depth))).collect()
+        }
+        PixelsByColorType::I1(_, ref palette) |
+        PixelsByColorType::I2(_, ref palette) |
+        PixelsByColorType::I4(_, ref palette) |
+        PixelsByColorType::I8(_, ref palette) => {
+            let indices = img.pixels.unpacked_samples(img.width, img.height).unwrap();
+            let mut pixels = Vec::with_capacity(indices.len());
+            for &index in &indices {
+                pixels.push(entry_rgba(palette.get(index as usize)?));
+            }
+            pixels
+        }
+    })
+}
+
+/// Converts a `bKGD` color given for pixels of `depth` bits, or for
+/// `palette` if there is one, to the layout of `target`, or returns `None`
+/// if it does not fit. `narrow` takes 16-bit samples to 8 bits, the same
+/// way as the pixels.
+pub(crate) fn convert_background(color: Background, depth: c_int, palette: Option<&[PaletteEntry]>,
+                                 target: PixelFormat,
+                                 gray_weights: (ffi::png_fixed_point, ffi::png_fixed_point),
+                                 narrow: fn(u16) -> u8) -> Option<Background> {
+    let fits = |sample: u16| depth == 16 || sample >> depth == 0;
+    let [r, g, b, _] = match (color, palette) {
+        (Background::Index(index), Some(palette)) => entry_rgba(palette.get(index as usize)?),
+        (Background::Gray(k), None) if fits(k) => [widen(k, depth); 4],
+        (Background::Rgb(r, g, b), None) if fits(r) && fits(g) && fits(b) => {
+            [widen(r, depth), widen(g, depth), widen(b, depth), 0]
+        }
+        _ => return None,
+    };
+    let depth = |sample: u16| if target.bit_depth() == 8 { narrow(sample) as u16 } else { sample };
+    Some(if target.is_color() {
+        Background::Rgb(depth(r), depth(g), depth(b))
+    } else {
+        Background::Gray(depth(gray([r, g, b], weights(gray_weights))))
+    })
+}
+
+/// Carries the precision of channels of `depth` bits over to the channels
+/// of the target, at most its bit depth. An added alpha channel is opaque
+/// and so fully significant.
+pub(crate) fn convert_significant_bits(bits: SignificantBits, depth: u8, is_color: bool, has_alpha: bool,
+                                       target: PixelFormat) -> SignificantBits {
+    let target_depth = target.bit_depth() as u8;
+    let gray = if is_color { cmp::max(bits.red, cmp::max(bits.green, bits.blue)) } else { bits.gray };
+    let (red, green, blue) = if is_color { (bits.red, bits.green, bits.blue) } else { (gray, gray, gray) };
+    let alpha = match (has_alpha, bits.alpha) {
+        (false, _) => target_depth,
+        (true, 0) => depth,
+        (true, alpha) => alpha,
+    };
+    let cap = |bits: u8| cmp::min(bits, target_depth);
+    SignificantBits {
+        red: if target.is_color() { cap(red) } else { 0 },
+        green: if target.is_color() { cap(green) } else { 0 },
+        blue: if target.is_color() { cap(blue) } else { 0 },
+        gray: if target.is_color() { 0 } else { cap(gray) },
+        alpha: if target.has_alpha() { cap(alpha) } else { 0 },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConvertOptions;
+    use super::super::{to_vec, Background, GrayWeights, Image, Metadata, PaletteEntry, PixelFormat};
+    use super::super::{load_png_with_options, DecodeOptions, PixelsByColorType, SignificantBits};
+    use super::super::PixelsByColorType::{ARGB8, BGR8, I2, K16, K2, K8, KA8, RGB16, RGB8, RGBA16, RGBA8};
+
+    const FORMATS: [PixelFormat; 11] = [PixelFormat::K8, PixelFormat::KA8, PixelFormat::RGB8, PixelFormat::RGBA8,
+                                        PixelFormat::BGR8, PixelFormat::BGRA8, PixelFormat::ARGB8, PixelFormat::K16,
+                                        PixelFormat::KA16, PixelFormat::RGB16, PixelFormat::RGBA16];
+
+    fn image(width: u32, pixels: PixelsByColorType) -> Image {
+        Image { width, height: 1, pixels, metadata: Default::default() }
+    }
+
+    #[test]
+    fn test_gray_and_color() {
+        let img = image(4, RGB8(vec![255, 0, 0, 0, 255, 0, 0, 0, 255, 9, 9, 9]));
+        assert_eq!(img.convert(PixelFormat::K8).pixels, K8(vec![54, 182, 18, 9]));
+        let options = ConvertOptions { to_gray: GrayWeights::Custom { red: 1.0, green: 0.0 }, ..Default::default() };
+        assert_eq!(img.convert_with_options(PixelFormat::K8, &options).unwrap().pixels, K8(vec![255, 0, 0, 9]));
+        let options = ConvertOptions { to_gray: GrayWeights::Custom { red: 0.8, green: 0.8 }, ..Default::default() };
+        assert!(img.convert_with_options(PixelFormat::K8, &options).is_err());
+
+        let img = image(2, K8(vec![7, 200]));
+        assert_eq!(img.convert(PixelFormat::BGR8).pixels, BGR8(vec![7, 7, 7, 200, 200, 200]));
+        assert_eq!(img.convert(PixelFormat::KA8).pixels, KA8(vec![7, 255, 200, 255]));
+    }
+
+    #[test]
+    fn test_alpha() {
+        let img = image(2, RGBA8(vec![255, 0, 0, 128, 10, 20, 30, 0]));
+        assert_eq!(img.convert(PixelFormat::RGB8).pixels, RGB8(vec![128, 0, 0, 0, 0, 0]));
+        let options = ConvertOptions { background: [0, 0, 255], ..Default::default() };
+        let composited = img.convert_with_options(PixelFormat::RGB8, &options).unwrap();
+        assert_eq!(composited.pixels, RGB8(vec![128, 0, 127, 0, 0, 255]));
+        assert_eq!(img.convert(PixelFormat::ARGB8).pixels, ARGB8(vec![128, 255, 0, 0, 0, 10, 20, 30]));
+
+        let img = image(1, RGB8(vec![1, 2, 3]));
+        assert_eq!(img.convert(PixelFormat::RGBA16).pixels, RGBA16(vec![257, 514, 771, 65535]));
+    }
+
+    #[test]
+    fn test_depth() {
+        let img = image(6, K16(vec![0, 128, 129, 25828, 25829, 65535]));
+        assert_eq!(img.convert(PixelFormat::K8).pixels, K8(vec![0, 0, 1, 100, 101, 255]));
+        let img = image(2, RGB8(vec![0, 1, 2, 128, 254, 255]));
+        assert_eq!(img.convert(PixelFormat::RGB16).pixels, RGB16(vec![0, 257, 514, 32896, 65278, 65535]));
+        assert_eq!(img.convert(PixelFormat::RGB16).convert(PixelFormat::RGB8), img);
+    }
+
+    #[test]
+    fn test_every_format() {
+        let img = image(3, RGBA8(vec![0, 64, 128, 255, 17, 34, 51, 68, 255, 255, 255, 0]));
+        for &format in FORMATS.iter() {
+            let converted = img.convert(format);
+            assert_eq!(converted.pixels.format(), Some(format));
+            // Only compositing and conversion to gray lose information.
+            let mut expected = img.clone();
+            if !format.has_alpha() {
+                expected = expected.convert(PixelFormat::RGB16);
+            }
+            if !format.is_color() {
+                expected = expected.convert(PixelFormat::KA16);
+            }
+            assert_eq!(converted.convert(PixelFormat::RGBA8), expected.convert(PixelFormat::RGBA8), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_packed_and_palette() {
+        let img = Image { width: 4, height: 2, pixels: K2(vec![0b00011011, 0b11000000]), metadata: Default::default() };
+        assert_eq!(img.convert(PixelFormat::K8).pixels, K8(vec![0, 85, 170, 255, 255, 0, 0, 0]));
+
+        let palette = vec![PaletteEntry { red: 255, green: 0, blue: 0, alpha: 255 },
+                           PaletteEntry { red: 0, green: 0, blue: 255, alpha: 0 }];
+        let img = image(3, I2(vec![0b00010000], palette.clone()));
+        assert_eq!(img.convert(PixelFormat::RGBA8).pixels, RGBA8(vec![255, 0, 0, 255, 0, 0, 255, 0, 255, 0, 0, 255]));
+        let img = image(3, I2(vec![0b00011000], palette));
+        assert!(img.convert_with_options(PixelFormat::RGBA8, &Default::default()).is_err());
+        assert!(image(3, RGB8(vec![0; 8])).convert_with_options(PixelFormat::K8, &Default::default()).is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata = Metadata {
+            background: Some(Background::Rgb(0, 0xffff, 0)),
+            significant_bits: Some(SignificantBits { red: 12, green: 12, blue: 10, gray: 0, alpha: 0 }),
+            ..Default::default()
+        };
+        let img = Image { width: 1, height: 1, pixels: RGB16(vec![1, 2, 3]), metadata };
+        for &format in FORMATS.iter() {
+            let mut converted = img.convert(format);
+            assert!(to_vec(&mut converted).is_ok(), "{:?}", format);
+        }
+        let converted = img.convert(PixelFormat::KA8);
+        assert_eq!(converted.metadata.background, Some(Background::Gray(182)));
+        assert_eq!(converted.metadata.significant_bits,
+                   Some(SignificantBits { red: 0, green: 0, blue: 0, gray: 8, alpha: 8 }));
+    }
+
+    #[test]
+    fn test_decoded_background() {
+        // A 16-bit RGB file with a blue bKGD, which decodes at the depth of the pixels.
+        let path = "png-sys/libpng-1.6.16/contrib/pngsuite/ftbbn2c16.png";
+        let options = DecodeOptions { keep_16_bit: true, ..Default::default() };
+        let wide = load_png_with_options(path, &options).unwrap();
+        assert_eq!(wide.metadata.background, Some(Background::Rgb(0, 0, 0xffff)));
+        assert_eq!(wide.convert(PixelFormat::RGB8).metadata.background, Some(Background::Rgb(0, 0, 0xff)));
+
+        let narrow = load_png_with_options(path, &Default::default()).unwrap();
+        assert_eq!(narrow.metadata.background, Some(Background::Rgb(0, 0, 0xff)));
+        assert_eq!(narrow.convert(PixelFormat::RGB16).metadata.background, Some(Background::Rgb(0, 0, 0xffff)));
+        for img in &[wide, narrow] {
+            for &format in FORMATS.iter() {
+                let mut converted = img.convert(format);
+                assert!(converted.metadata.background.is_some(), "{:?}", format);
+                assert!(to_vec(&mut converted).is_ok(), "{:?}", format);
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Editing of ancillary chunks without touching the image data.
+//!
+//! Critical chunks and `tRNS`, which decodes to alpha, are always copied
+//! verbatim, so the decoded pixels of the output are byte-identical to
+//! those of the input.
+
+use chunks::{chunks, Chunk, ChunkWriter};
+
+/// Selects the ancillary chunks removed by `strip_metadata`. `tRNS` is
+/// kept whatever the policy.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum StripPolicy {
+    /// Remove the listed chunk types and keep everything else.
+    Remove(Vec<[u8; 4]>),
+    /// Keep only the listed ancillary chunk types.
+    Keep(Vec<[u8; 4]>),
+}
+
+impl StripPolicy {
+    /// Removes text, EXIF and modification time chunks, which may identify
+    /// the author, device or location of an image.
+    pub fn privacy() -> StripPolicy {
+        StripPolicy::Remove(vec![*b"tEXt", *b"zTXt", *b"iTXt", *b"eXIf", *b"tIME"])
+    }
+
+    /// Keeps only the chunks that affect how the pixels are displayed.
+    pub fn display_only() -> StripPolicy {
+        StripPolicy::Keep(vec![*b"tRNS", *b"gAMA", *b"cHRM", *b"sRGB", *b"iCCP", *b"sBIT"])
+    }
+
+    fn keeps(&self, chunk: &Chunk) -> bool {
+        if chunk.is_critical() || &chunk.chunk_type == b"tRNS" {
+            return true;
+        }
+        match *self {
+            StripPolicy::Remove(ref types) => !types.contains(&chunk.chunk_type),
+            StripPolicy::Keep(ref types) => types.contains(&chunk.chunk_type),
+        }
+    }
+}
+
+impl Default for StripPolicy {
+    fn default() -> StripPolicy {
+        StripPolicy::privacy()
+    }
+}
+
+/// Units of the physical pixel dimensions stored in `pHYs`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PhysUnit {
+    /// Only the aspect ratio is known.
+    Unknown,
+    Meter,
+}
+
+/// Rewrites `input` without the ancillary chunks rejected by `policy`.
+pub fn strip_metadata(input: &[u8], policy: &StripPolicy) -> Result<Vec<u8>, String> {
+    rewrite(input, |chunk, _| Ok(policy.keeps(chunk)))
+}
+
+/// Sets the `tEXt` entry for `keyword`, replacing any existing entry with
+/// the same keyword.
+pub fn set_text(input: &[u8], keyword: &str, text: &str) -> Result<Vec<u8>, String> {
+    if keyword.is_empty() || keyword.len() > 79 || keyword.contains('\0') {
+        return Err("keyword must be 1-79 bytes without nul".to_string());
+    }
+    if !keyword.is_ascii() || !text.is_ascii() || text.contains('\0') {
+        return Err("tEXt only supports ascii without nul".to_string());
+    }
+    let mut data = keyword.as_bytes().to_vec();
+    data.push(0);
+    data.extend(text.as_bytes());
+
+    let mut written = false;
+    rewrite(input, |chunk, writer| {
+        if &chunk.chunk_type == b"tEXt" && chunk.data.split(|&b| b == 0).next() == Some(keyword.as_bytes()) {
+            return Ok(false);
+        }
+        if !written && &chunk.chunk_type == b"IDAT" {
+            written = true;
+            writer.write_chunk(b"tEXt", &data)?;
+        }
+        Ok(true)
+    })
+}
+
+/// Sets the physical pixel dimensions, replacing any existing `pHYs` chunk.
+pub fn set_phys(input: &[u8], x: u32, y: u32, unit: PhysUnit) -> Result<Vec<u8>, String> {
+    let mut data = Vec::with_capacity(9);
+    data.extend(&x.to_be_bytes());
+    data.extend(&y.to_be_bytes());
+    data.push(match unit {
+        PhysUnit::Unknown => 0,
+        PhysUnit::Meter => 1,
+    });
+
+    let mut written = false;
+    rewrite(input, |chunk, writer| {
+        if &chunk.chunk_type == b"pHYs" {
+            return Ok(false);
+        }
+        if !written && &chunk.chunk_type == b"IDAT" {
+            written = true;
+            writer.write_chunk(b"pHYs", &data)?;
+        }
+        Ok(true)
+    })
+}
+
+/// Copies every chunk of `input` for which `f` returns true. `f` may write
+/// additional chunks before the current one.
+fn rewrite<F>(input: &[u8], mut f: F) -> Result<Vec<u8>, String>
+    where F: FnMut(&Chunk, &mut ChunkWriter<Vec<u8>>) -> Result<bool, String> {
+    let mut writer = ChunkWriter::new(Vec::with_capacity(input.len()))?;
+    for chunk in chunks(input)? {
+        let chunk = chunk?;
+        if f(&chunk, &mut writer)? {
+            writer.copy_chunk(&chunk)?;
+        }
+    }
+    Ok(writer.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Read;
+
+    use chunks::chunks;
+    use super::{set_phys, set_text, strip_metadata, PhysUnit, StripPolicy};
+    use super::super::{load_png_from_memory, to_vec, Image, PaletteEntry};
+    use super::super::PixelsByColorType::{I8, RGBA8};
+
+    fn chunk_types(png: &[u8]) -> Vec<[u8; 4]> {
+        chunks(png).unwrap().map(|c| c.unwrap().chunk_type).collect()
+    }
+
+    #[test]
+    fn test_edit_and_strip() {
+        let mut png = vec![];
+        File::open("test/gray.png").unwrap().read_to_end(&mut png).unwrap();
+
+        let edited = set_text(&png, "Author", "someone").unwrap();
+        let edited = set_text(&edited, "Author", "someone else").unwrap();
+        let edited = set_phys(&edited, 2835, 2835, PhysUnit::Meter).unwrap();
+        let types = chunk_types(&edited);
+        assert_eq!(types.iter().filter(|t| *t == b"tEXt").count(), 1);
+        assert_eq!(types.iter().filter(|t| *t == b"pHYs").count(), 1);
+        assert!(types.iter().position(|t| t == b"pHYs") < types.iter().position(|t| t == b"IDAT"));
+
+        let stripped = strip_metadata(&edited, &StripPolicy::privacy()).unwrap();
+        let types = chunk_types(&stripped);
+        assert!(!types.contains(b"tEXt"));
+        assert!(types.contains(b"pHYs"));
+
+        let stripped = strip_metadata(&edited, &StripPolicy::Keep(vec![])).unwrap();
+        assert!(chunk_types(&stripped).iter().all(|t| t[0] & 0x20 == 0));
+
+        let original = load_png_from_memory(&png).unwrap();
+        let decoded = load_png_from_memory(&stripped).unwrap();
+        match (original.pixels, decoded.pixels) {
+            (RGBA8(original), RGBA8(decoded)) => assert!(original == decoded),
+            _ => panic!("Expected RGBA8"),
+        }
+    }
+
+    #[test]
+    fn test_strip_keeps_transparency() {
+        let palette = vec![
+            PaletteEntry { red: 255, green: 0, blue: 0, alpha: 255 },
+            PaletteEntry { red: 0, green: 0, blue: 255, alpha: 0 },
+        ];
+        let mut img = Image { width: 2, height: 1, pixels: I8(vec![0, 1], palette), metadata: Default::default() };
+        let png = to_vec(&mut img).unwrap();
+        assert!(chunk_types(&png).contains(b"tRNS"));
+        let original = load_png_from_memory(&png).unwrap().pixels;
+        assert_eq!(original, RGBA8(vec![255, 0, 0, 255, 0, 0, 255, 0]));
+
+        for policy in &[StripPolicy::Keep(vec![]), StripPolicy::Remove(vec![*b"tRNS"]), StripPolicy::privacy()] {
+            let stripped = strip_metadata(&png, policy).unwrap();
+            assert!(chunk_types(&stripped).contains(b"tRNS"), "{:?}", policy);
+            assert_eq!(load_png_from_memory(&stripped).unwrap().pixels, original);
+        }
+    }
+}
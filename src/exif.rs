@@ -0,0 +1,208 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Support for the `eXIf` chunk, which libpng 1.6.16 does not know about.
+
+use std::mem;
+
+const ORIENTATION_TAG: u16 = 0x0112;
+const TYPE_SHORT: u16 = 3;
+
+/// Raw EXIF data in TIFF format, as stored in an `eXIf` chunk.
+#[cfg_attr(feature="serde-serialization", derive(Deserialize, Serialize))]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Exif {
+    #[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))]
+    pub data: Vec<u8>,
+}
+
+/// The transformation that has to be applied to the stored pixels to
+/// display the image upright, as given by the EXIF orientation tag.
+#[cfg_attr(feature="serde-serialization", derive(Deserialize, Serialize))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Orientation {
+    Normal = 1,
+    FlipHorizontal = 2,
+    Rotate180 = 3,
+    FlipVertical = 4,
+    Transpose = 5,
+    Rotate90 = 6,
+    Transverse = 7,
+    Rotate270 = 8,
+}
+
+impl Orientation {
+    pub fn from_u16(value: u16) -> Option<Orientation> {
+        Some(match value {
+            1 => Orientation::Normal,
+            2 => Orientation::FlipHorizontal,
+            3 => Orientation::Rotate180,
+            4 => Orientation::FlipVertical,
+            5 => Orientation::Transpose,
+            6 => Orientation::Rotate90,
+            7 => Orientation::Transverse,
+            8 => Orientation::Rotate270,
+            _ => return None,
+        })
+    }
+
+    /// Whether the width and height of the image are exchanged.
+    pub fn swaps_dimensions(self) -> bool {
+        self as u16 >= 5
+    }
+}
+
+impl Exif {
+    pub fn new(data: Vec<u8>) -> Exif {
+        Exif { data }
+    }
+
+    /// Returns the orientation stored in the first IFD, if any.
+    pub fn orientation(&self) -> Option<Orientation> {
+        let (offset, big_endian) = self.find_orientation()?;
+        Orientation::from_u16(read_u16(&self.data[offset..], big_endian))
+    }
+
+    /// Overwrites an existing orientation tag. Returns false if the data
+    /// has no orientation tag to update.
+    pub fn set_orientation(&mut self, orientation: Orientation) -> bool {
+        match self.find_orientation() {
+            Some((offset, big_endian)) => {
+                let value = orientation as u16;
+                let bytes = if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+                self.data[offset..offset + 2].copy_from_slice(&bytes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the offset of the orientation value and the byte order.
+    fn find_orientation(&self) -> Option<(usize, bool)> {
+        // Some writers keep the "Exif\0\0" header from JPEG APP1 segments.
+        let start = if self.data.starts_with(b"Exif\0\0") { 6 } else { 0 };
+        let tiff = &self.data[start..];
+        if tiff.len() < 8 {
+            return None;
+        }
+        let big_endian = match &tiff[0..4] {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        let ifd = read_u32(&tiff[4..], big_endian) as usize;
+        if tiff.len() - 2 < ifd {
+            return None;
+        }
+        let count = read_u16(&tiff[ifd..], big_endian) as usize;
+        for i in 0..count {
+            let entry = ifd + 2 + i * 12;
+            if entry + 12 > tiff.len() {
+                return None;
+            }
+            if read_u16(&tiff[entry..], big_endian) == ORIENTATION_TAG &&
+               read_u16(&tiff[entry + 2..], big_endian) == TYPE_SHORT &&
+               read_u32(&tiff[entry + 4..], big_endian) == 1 {
+                return Some((start + entry + 8, big_endian));
+            }
+        }
+        None
+    }
+}
+
+fn read_u16(bytes: &[u8], big_endian: bool) -> u16 {
+    let value = [bytes[0], bytes[1]];
+    if big_endian { u16::from_be_bytes(value) } else { u16::from_le_bytes(value) }
+}
+
+fn read_u32(bytes: &[u8], big_endian: bool) -> u32 {
+    let value = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    if big_endian { u32::from_be_bytes(value) } else { u32::from_le_bytes(value) }
+}
+
+/// Applies `orientation` to an image of `width` x `height` pixels with
+/// `channels` samples each, updating the dimensions.
+pub fn orient<T: Copy>(data: &mut Vec<T>, width: &mut u32, height: &mut u32, channels: usize,
+                       orientation: Orientation) {
+    if orientation == Orientation::Normal {
+        return;
+    }
+    let (w, h) = (*width as usize, *height as usize);
+    let (dw, dh) = if orientation.swaps_dimensions() { (h, w) } else { (w, h) };
+    let mut out = Vec::with_capacity(data.len());
+    for dy in 0..dh {
+        for dx in 0..dw {
+            let (sx, sy) = match orientation {
+                Orientation::Normal => (dx, dy),
+                Orientation::FlipHorizontal => (w - 1 - dx, dy),
+                Orientation::Rotate180 => (w - 1 - dx, h - 1 - dy),
+                Orientation::FlipVertical => (dx, h - 1 - dy),
+                Orientation::Transpose => (dy, dx),
+                Orientation::Rotate90 => (dy, h - 1 - dx),
+                Orientation::Transverse => (w - 1 - dy, h - 1 - dx),
+                Orientation::Rotate270 => (w - 1 - dy, dx),
+            };
+            let src = (sy * w + sx) * channels;
+            out.extend_from_slice(&data[src..src + channels]);
+        }
+    }
+    mem::swap(data, &mut out);
+    *width = dw as u32;
+    *height = dh as u32;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{orient, Exif, Orientation};
+
+    fn exif_with_orientation(big_endian: bool, value: u16) -> Exif {
+        let mut data = vec![];
+        if big_endian {
+            data.extend(b"MM\0*\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01");
+            data.extend(&value.to_be_bytes());
+        } else {
+            data.extend(b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0");
+            data.extend(&value.to_le_bytes());
+        }
+        data.extend(&[0; 6]);
+        Exif::new(data)
+    }
+
+    #[test]
+    fn test_orientation() {
+        assert_eq!(exif_with_orientation(true, 6).orientation(), Some(Orientation::Rotate90));
+        let mut exif = exif_with_orientation(false, 3);
+        assert_eq!(exif.orientation(), Some(Orientation::Rotate180));
+        assert!(exif.set_orientation(Orientation::Normal));
+        assert_eq!(exif.orientation(), Some(Orientation::Normal));
+        assert_eq!(Exif::new(vec![1, 2, 3]).orientation(), None);
+    }
+
+    #[test]
+    fn test_orient() {
+        // 1 2 3
+        // 4 5 6
+        let image = vec![1, 2, 3, 4, 5, 6];
+        let expected = [
+            (Orientation::FlipHorizontal, vec![3, 2, 1, 6, 5, 4]),
+            (Orientation::Rotate180, vec![6, 5, 4, 3, 2, 1]),
+            (Orientation::FlipVertical, vec![4, 5, 6, 1, 2, 3]),
+            (Orientation::Transpose, vec![1, 4, 2, 5, 3, 6]),
+            (Orientation::Rotate90, vec![4, 1, 5, 2, 6, 3]),
+            (Orientation::Transverse, vec![6, 3, 5, 2, 4, 1]),
+            (Orientation::Rotate270, vec![3, 6, 2, 5, 1, 4]),
+        ];
+        for &(orientation, ref pixels) in expected.iter() {
+            let (mut data, mut width, mut height) = (image.clone(), 3, 2);
+            orient(&mut data, &mut width, &mut height, 1, orientation);
+            assert_eq!(&data, pixels);
+            assert_eq!((width, height), if orientation.swaps_dimensions() { (2, 3) } else { (3, 2) });
+        }
+    }
+}
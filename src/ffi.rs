@@ -9,16 +9,45 @@
 
 #![allow(non_camel_case_types)]
 
-use libc::{c_int, size_t, c_void, c_char};
+use libc::{c_int, c_uint, c_ulong, size_t, c_void, c_char};
 
 pub const TRANSFORM_IDENTITY: c_int = 0;
+pub const TRANSFORM_PACKING: c_int = 0x0004;
+pub const TRANSFORM_SHIFT: c_int = 0x0040;
+pub const TRANSFORM_SWAP_ENDIAN: c_int = 0x0200;
 
 pub const FILTER_NONE: c_int = 0;
 
+// Row filters libpng may choose from, for png_set_filter.
+pub const FILTER_FLAG_NONE: c_int = 0x08;
+pub const FILTER_FLAG_SUB: c_int = 0x10;
+pub const FILTER_FLAG_UP: c_int = 0x20;
+pub const FILTER_FLAG_AVG: c_int = 0x40;
+pub const FILTER_FLAG_PAETH: c_int = 0x80;
+pub const ALL_FILTERS: c_int = 0xf8;
+
+pub const Z_DEFAULT_STRATEGY: c_int = 0;
+pub const Z_FILTERED: c_int = 1;
+pub const Z_HUFFMAN_ONLY: c_int = 2;
+pub const Z_RLE: c_int = 3;
+pub const Z_FIXED: c_int = 4;
+
+pub const Z_NO_FLUSH: c_int = 0;
+pub const Z_SYNC_FLUSH: c_int = 2;
+pub const Z_FINISH: c_int = 4;
+pub const Z_OK: c_int = 0;
+pub const Z_STREAM_END: c_int = 1;
+pub const Z_BUF_ERROR: c_int = -5;
+pub const Z_DEFLATED: c_int = 8;
+pub const Z_DEFAULT_COMPRESSION: c_int = -1;
+
 pub const INTERLACE_NONE: c_int = 0;
 
 pub const COMPRESSION_TYPE_DEFAULT: c_int = 0;
 
+pub const COLOR_MASK_COLOR: c_int = 2;
+pub const COLOR_MASK_ALPHA: c_int = 4;
+
 pub const COLOR_TYPE_GRAY: c_int = 0;
 pub const COLOR_TYPE_RGB: c_int = 2;
 pub const COLOR_TYPE_PALETTE: c_int = 3;
@@ -27,12 +56,82 @@ pub const COLOR_TYPE_GA: c_int = 4;
 pub const COLOR_TYPE_RGB_ALPHA: c_int = 6;
 pub const COLOR_TYPE_RGBA: c_int = 6;
 
+pub const FILLER_BEFORE: c_int = 0;
 pub const FILLER_AFTER: c_int = 1;
+
+pub const BACKGROUND_GAMMA_SCREEN: c_int = 1;
+pub const BACKGROUND_GAMMA_FILE: c_int = 2;
+
+pub const FP_1: png_fixed_point = 100000;
+
+pub const ERROR_ACTION_NONE: c_int = 1;
+
 #[allow(non_upper_case_globals)]
 pub const INFO_tRNS: c_int = 0x0010;
 
+pub const HAVE_IHDR: u8 = 0x01;
+
 pub type png_struct = c_void;
 pub type png_info = c_void;
+pub type png_fixed_point = i32;
+
+#[repr(C)]
+pub struct png_color_16 {
+    pub index: u8,
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
+    pub gray: u16,
+}
+
+#[repr(C)]
+pub struct png_color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct png_color_8 {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub gray: u8,
+    pub alpha: u8,
+}
+
+#[repr(C)]
+pub struct png_time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+#[repr(C)]
+pub struct png_unknown_chunk {
+    pub name: [u8; 5],
+    pub data: *mut u8,
+    pub size: size_t,
+    pub location: u8,
+}
+
+/// Declares libpng functions under their usual names. The vendored libpng
+/// is built with every symbol prefixed with `RUST_`, so that it cannot clash
+/// with another copy of libpng in the same process.
+macro_rules! libpng {
+    ($(pub fn $name:ident($($args:tt)*) $(-> $ret:ty)*;)*) => {
+        extern "C" {
+            $(
+                #[cfg_attr(png_prefix, link_name = concat!("RUST_", stringify!($name)))]
+                pub fn $name($($args)*) $(-> $ret)*;
+            )*
+        }
+    }
+}
 
 extern {
     // libc routines needed
@@ -40,48 +139,109 @@ extern {
 
     // shim routines
     pub fn pngshim_jmpbuf(pnt_ptr: *mut png_struct) -> *mut c_void;
+    pub fn pngshim_read_image(png_ptr: *mut png_struct, rows: *mut *mut u8) -> c_int;
+    pub fn pngshim_read_end(png_ptr: *mut png_struct, info_ptr: *mut png_info) -> c_int;
+}
+
+// libpng routines
+libpng! {
+    pub fn png_get_header_ver(png_ptr: *mut png_struct) -> *mut c_char;
+    pub fn png_sig_cmp(sig: *const u8, start: size_t, num_to_check: size_t) -> c_int;
+
+    pub fn png_create_info_struct(png_ptr: *mut png_struct) -> *mut png_info;
+    pub fn png_get_io_ptr(png_ptr: *mut png_struct) -> *mut c_void;
+    pub fn png_set_sig_bytes(png_ptr: *mut png_struct, num_bytes: c_int);
+
+    pub fn png_create_read_struct(user_png_ver: *const c_char, error_ptr: *mut c_void, error_fn: *mut u8, warn_fn: *mut u8) -> *mut png_struct;
+    pub fn png_destroy_read_struct(png_ptr_ptr: *mut *mut png_struct, info_ptr_ptr: *mut *mut png_info, end_info_ptr_ptr: *mut *mut png_info);
+    pub fn png_set_read_fn(png_ptr: *mut png_struct, io_ptr: *mut c_void, read_data_fn: extern "C" fn(*mut png_struct, *mut u8, size_t));
+    pub fn png_read_info(png_ptr: *mut png_struct, info_ptr: *mut png_info);
+    pub fn png_read_update_info(png_ptr: *mut png_struct, info_ptr: *mut png_info);
+    pub fn png_read_image(png_ptr: *mut png_struct, row_pointers: *mut *mut u8);
+    pub fn png_read_end(png_ptr: *mut png_struct, info_ptr: *mut png_info);
+    pub fn png_read_png(png_ptr: *mut png_struct, info_ptr: *mut png_info, transforms: c_int, params: *mut c_void);
+
+    pub fn png_create_write_struct(user_png_ver: *const c_char, error_ptr: *mut c_void, error_fn: *mut u8, warn_fn: *mut u8) -> *mut png_struct;
+    pub fn png_destroy_write_struct(png_ptr_ptr: *mut *mut png_struct, info_ptr_ptr: *mut *mut png_info);
+    pub fn png_set_write_fn(png_ptr: *mut png_struct, io_ptr: *mut c_void, write_data_fn: extern "C" fn(*mut png_struct, *mut u8, size_t), output_flush_ptr: extern "C" fn(*mut png_struct));
+    pub fn png_write_png(pnt_ptr: *mut png_struct, info_ptr: *mut png_info, transforms: c_int, params: *mut c_void); // ??
+
+    pub fn png_get_IHDR(png_ptr: *mut png_struct, info_ptr: *mut png_info, width: *mut u32, height: *mut u32, bit_depth: *mut c_int, color_type: *mut c_int, interlace_method: *mut c_int, compression_method: *mut c_int, filter_method: *mut c_int) -> u32;
+    pub fn png_get_pHYs(png_ptr: *mut png_struct, info_ptr: *mut png_info, res_x: *mut u32, res_y: *mut u32, unit_type: *mut c_int) -> u32;
+    pub fn png_get_PLTE(png_ptr: *mut png_struct, info_ptr: *mut png_info, palette: *mut *mut png_color, num_palette: *mut c_int) -> u32;
+    pub fn png_get_bKGD(png_ptr: *mut png_struct, info_ptr: *mut png_info, background: *mut *mut png_color_16) -> u32;
+    pub fn png_get_sBIT(png_ptr: *mut png_struct, info_ptr: *mut png_info, sig_bit: *mut *mut png_color_8) -> u32;
+    pub fn png_get_tIME(png_ptr: *mut png_struct, info_ptr: *mut png_info, mod_time: *mut *mut png_time) -> u32;
+    pub fn png_get_image_width(png_ptr: *mut png_struct, info_ptr: *mut png_info) -> u32;
+    pub fn png_get_image_height(png_ptr: *mut png_struct, info_ptr: *mut png_info) -> u32;
+    pub fn png_get_bit_depth(png_ptr: *mut png_struct, info_ptr: *mut png_info) -> u8;
+    pub fn png_get_color_type(png_ptr: *mut png_struct, info_ptr: *mut png_info) -> u8;
+    pub fn png_get_valid(png_ptr: *mut png_struct, info_ptr: *mut png_info, flag: u32) -> u32;
+    pub fn png_get_rows(png_ptr: *mut png_struct, info_ptr: *mut png_info) -> *mut *mut u8;
+
+    pub fn png_set_IHDR(png_ptr: *mut png_struct, info_ptr: *mut png_info, width: u32, height: u32, bit_depth: c_int, color_type: c_int, interlace_method: c_int, compression_method: c_int, filter_method: c_int);
+    pub fn png_set_pHYs(png_ptr: *mut png_struct, info_ptr: *mut png_info, res_x: u32, res_y: u32, unit_type: c_int);
+    pub fn png_set_bKGD(png_ptr: *mut png_struct, info_ptr: *mut png_info, background: *const png_color_16);
+    pub fn png_set_PLTE(png_ptr: *mut png_struct, info_ptr: *mut png_info, palette: *const png_color, num_palette: c_int);
+    pub fn png_set_tRNS(png_ptr: *mut png_struct, info_ptr: *mut png_info, trans_alpha: *const u8, num_trans: c_int, trans_color: *const png_color_16);
+    pub fn png_set_sBIT(png_ptr: *mut png_struct, info_ptr: *mut png_info, sig_bit: *const png_color_8);
+    pub fn png_set_tIME(png_ptr: *mut png_struct, info_ptr: *mut png_info, mod_time: *const png_time);
+    pub fn png_set_unknown_chunks(png_ptr: *mut png_struct, info_ptr: *mut png_info, unknowns: *const png_unknown_chunk, num_unknowns: c_int);
+    pub fn png_set_rows(png_ptr: *mut png_struct, info_ptr: *mut png_info, row_pointers: *mut *mut u8);
+    pub fn png_set_filter(png_ptr: *mut png_struct, method: c_int, filters: c_int);
+    pub fn png_set_compression_level(png_ptr: *mut png_struct, level: c_int);
+    pub fn png_set_compression_strategy(png_ptr: *mut png_struct, strategy: c_int);
+
+    pub fn png_set_packing(png_ptr: *mut png_struct);
+    pub fn png_set_palette_to_rgb(png_ptr: *mut png_struct);
+    pub fn png_set_expand_gray_1_2_4_to_8(png_ptr: *mut png_struct);
+    pub fn png_set_gray_to_rgb(png_ptr: *mut png_struct);
+    pub fn png_set_tRNS_to_alpha(png_ptr: *mut png_struct);
+    pub fn png_set_add_alpha(png_ptr: *mut png_struct, val: u32, flag: c_int);
+    pub fn png_set_filler(png_ptr: *mut png_struct, val: u32, flag: c_int);
+    pub fn png_set_interlace_handling(png_ptr: *mut png_struct);
+    pub fn png_set_strip_16(png_ptr: *mut png_struct);
+    pub fn png_set_expand_16(png_ptr: *mut png_struct);
+    pub fn png_set_strip_alpha(png_ptr: *mut png_struct);
+    pub fn png_set_bgr(png_ptr: *mut png_struct);
+    pub fn png_set_swap_alpha(png_ptr: *mut png_struct);
+    pub fn png_set_rgb_to_gray_fixed(png_ptr: *mut png_struct, error_action: c_int, red: png_fixed_point, green: png_fixed_point);
+    pub fn png_get_rgb_to_gray_status(png_ptr: *const png_struct) -> u8;
+    pub fn png_error(png_ptr: *mut png_struct, error_message: *const c_char) -> !;
+    pub fn png_set_swap(png_ptr: *mut png_struct);
+    pub fn png_set_shift(png_ptr: *mut png_struct, true_bits: *const png_color_8);
+    pub fn png_set_background_fixed(png_ptr: *mut png_struct, background_color: *const png_color_16, background_gamma_code: c_int, need_expand: c_int, background_gamma: png_fixed_point);
+}
+
+/// zlib's stream state. The allocator fields may be null to use malloc.
+#[repr(C)]
+pub struct z_stream {
+    pub next_in: *const u8,
+    pub avail_in: c_uint,
+    pub total_in: c_ulong,
+    pub next_out: *mut u8,
+    pub avail_out: c_uint,
+    pub total_out: c_ulong,
+    pub msg: *const c_char,
+    pub state: *mut c_void,
+    pub zalloc: *mut c_void,
+    pub zfree: *mut c_void,
+    pub opaque: *mut c_void,
+    pub data_type: c_int,
+    pub adler: c_ulong,
+    pub reserved: c_ulong,
+}
 
-    // libpng routines
-    pub fn RUST_png_get_header_ver(png_ptr: *mut png_struct) -> *mut c_char;
-    pub fn RUST_png_sig_cmp(sig: *const u8, start: size_t, num_to_check: size_t) -> c_int;
-
-    pub fn RUST_png_create_info_struct(png_ptr: *mut png_struct) -> *mut png_info;
-    pub fn RUST_png_get_io_ptr(png_ptr: *mut png_struct) -> *mut c_void;
-    pub fn RUST_png_set_sig_bytes(png_ptr: *mut png_struct, num_bytes: c_int);
-
-    pub fn RUST_png_create_read_struct(user_png_ver: *const c_char, error_ptr: *mut c_void, error_fn: *mut u8, warn_fn: *mut u8) -> *mut png_struct;
-    pub fn RUST_png_destroy_read_struct(png_ptr_ptr: *mut *mut png_struct, info_ptr_ptr: *mut *mut png_info, end_info_ptr_ptr: *mut *mut png_info);
-    pub fn RUST_png_set_read_fn(png_ptr: *mut png_struct, io_ptr: *mut c_void, read_data_fn: extern "C" fn(*mut png_struct, *mut u8, size_t));
-    pub fn RUST_png_read_info(png_ptr: *mut png_struct, info_ptr: *mut png_info);
-    pub fn RUST_png_read_update_info(png_ptr: *mut png_struct, info_ptr: *mut png_info);
-    pub fn RUST_png_read_image(png_ptr: *mut png_struct, row_pointers: *mut *mut u8);
-    pub fn RUST_png_read_png(png_ptr: *mut png_struct, info_ptr: *mut png_info, transforms: c_int, params: *mut c_void);
-
-    pub fn RUST_png_create_write_struct(user_png_ver: *const c_char, error_ptr: *mut c_void, error_fn: *mut u8, warn_fn: *mut u8) -> *mut png_struct;
-    pub fn RUST_png_destroy_write_struct(png_ptr_ptr: *mut *mut png_struct, info_ptr_ptr: *mut *mut png_info);
-    pub fn RUST_png_set_write_fn(png_ptr: *mut png_struct, io_ptr: *mut c_void, write_data_fn: extern "C" fn(*mut png_struct, *mut u8, size_t), output_flush_ptr: extern "C" fn(*mut png_struct));
-    pub fn RUST_png_write_png(pnt_ptr: *mut png_struct, info_ptr: *mut png_info, transforms: c_int, params: *mut c_void); // ??
-
-    pub fn RUST_png_get_IHDR(png_ptr: *mut png_struct, info_ptr: *mut png_info, width: *mut u32, height: *mut u32, bit_depth: *mut c_int, color_type: *mut c_int, interlace_method: *mut c_int, compression_method: *mut c_int, filter_method: *mut c_int) -> u32;
-    pub fn RUST_png_get_pHYs(png_ptr: *mut png_struct, info_ptr: *mut png_info, res_x: *mut u32, res_y: *mut u32, unit_type: *mut c_int) -> u32;
-    pub fn RUST_png_get_image_width(png_ptr: *mut png_struct, info_ptr: *mut png_info) -> u32;
-    pub fn RUST_png_get_image_height(png_ptr: *mut png_struct, info_ptr: *mut png_info) -> u32;
-    pub fn RUST_png_get_bit_depth(png_ptr: *mut png_struct, info_ptr: *mut png_info) -> u8;
-    pub fn RUST_png_get_color_type(png_ptr: *mut png_struct, info_ptr: *mut png_info) -> u8;
-    pub fn RUST_png_get_valid(png_ptr: *mut png_struct, info_ptr: *mut png_info, flag: u32) -> u32;
-    pub fn RUST_png_get_rows(png_ptr: *mut png_struct, info_ptr: *mut png_info) -> *mut *mut u8;
-
-    pub fn RUST_png_set_IHDR(png_ptr: *mut png_struct, info_ptr: *mut png_info, width: u32, height: u32, bit_depth: c_int, color_type: c_int, interlace_method: c_int, compression_method: c_int, filter_method: c_int);
-    pub fn RUST_png_set_pHYs(png_ptr: *mut png_struct, info_ptr: *mut png_info, res_x: u32, res_y: u32, unit_type: c_int);
-    pub fn RUST_png_set_rows(png_ptr: *mut png_struct, info_ptr: *mut png_info, row_pointers: *mut *mut u8);
-
-    pub fn RUST_png_set_packing(png_ptr: *mut png_struct);
-    pub fn RUST_png_set_palette_to_rgb(png_ptr: *mut png_struct);
-    pub fn RUST_png_set_expand_gray_1_2_4_to_8(png_ptr: *mut png_struct);
-    pub fn RUST_png_set_gray_to_rgb(png_ptr: *mut png_struct);
-    pub fn RUST_png_set_tRNS_to_alpha(png_ptr: *mut png_struct);
-    pub fn RUST_png_set_add_alpha(png_ptr: *mut png_struct, val: u32, flag: c_int);
-    pub fn RUST_png_set_filler(png_ptr: *mut png_struct, val: u32, flag: c_int);
-    pub fn RUST_png_set_interlace_handling(png_ptr: *mut png_struct);
-    pub fn RUST_png_set_strip_16(png_ptr: *mut png_struct);
+// zlib routines, linked through libpng
+extern "C" {
+    pub fn zlibVersion() -> *const c_char;
+    pub fn deflateInit2_(strm: *mut z_stream, level: c_int, method: c_int, window_bits: c_int, mem_level: c_int, strategy: c_int, version: *const c_char, stream_size: c_int) -> c_int;
+    pub fn deflateSetDictionary(strm: *mut z_stream, dictionary: *const u8, dict_length: c_uint) -> c_int;
+    pub fn deflate(strm: *mut z_stream, flush: c_int) -> c_int;
+    pub fn deflateBound(strm: *mut z_stream, source_len: c_ulong) -> c_ulong;
+    pub fn deflateEnd(strm: *mut z_stream) -> c_int;
+    pub fn inflateInit_(strm: *mut z_stream, version: *const c_char, stream_size: c_int) -> c_int;
+    pub fn inflate(strm: *mut z_stream, flush: c_int) -> c_int;
+    pub fn inflateEnd(strm: *mut z_stream) -> c_int;
+    pub fn adler32(adler: c_ulong, buf: *const u8, len: c_uint) -> c_ulong;
 }
@@ -9,40 +9,797 @@
 
 #![crate_name = "png"]
 #![crate_type = "rlib"]
-#![cfg_attr(feature="serde-serialization", feature(custom_derive, plugin))]
-#![cfg_attr(feature="serde-serialization", plugin(serde_macros))]
 
 extern crate libc;
 extern crate png_sys;
 
 #[cfg(feature="serde-serialization")]
 extern crate serde;
+#[cfg(feature="serde-serialization")]
+#[macro_use]
+extern crate serde_derive;
 
-use libc::{c_int, size_t};
+use libc::{c_char, c_int, c_void, size_t};
 use std::error::Error;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::cmp;
 use std::iter::repeat;
 use std::mem;
 use std::path::Path;
 use std::ptr;
 use std::slice;
 
+pub mod batch;
+pub mod chunks;
+pub mod convert;
+pub mod edit;
+pub mod exif;
 pub mod ffi;
+pub mod optimize;
+mod pack;
+mod parallel;
+pub mod quantize;
+mod reduce;
+#[cfg(feature="serde-serialization")]
+mod serde_pixels;
+pub mod time;
+
+pub use batch::{decode_batch, BatchOptions, DecodeBatch};
+pub use convert::ConvertOptions;
+pub use edit::{set_phys, set_text, strip_metadata, PhysUnit, StripPolicy};
+pub use exif::{Exif, Orientation};
+pub use optimize::{optimize, optimize_with_options, Effort, OptimizeOptions};
+pub use quantize::{quantize, QuantizeOptions};
+pub use time::PngTime;
 
 #[cfg_attr(feature="serde-serialization", derive(Deserialize, Serialize))]
+#[derive(Clone, PartialEq, Debug)]
 pub enum PixelsByColorType {
-    K8(Vec<u8>),
-    KA8(Vec<u8>),
-    RGB8(Vec<u8>),
-    RGBA8(Vec<u8>),
+    K8(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>),
+    KA8(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>),
+    RGB8(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>),
+    RGBA8(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>),
+    /// 8-bit color with the channels in another order, stored in the file
+    /// as `RGB8` and `RGBA8`.
+    BGR8(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>),
+    BGRA8(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>),
+    ARGB8(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>),
+    /// 16-bit samples in native byte order.
+    K16(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::samples16"))] Vec<u16>),
+    KA16(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::samples16"))] Vec<u16>),
+    RGB16(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::samples16"))] Vec<u16>),
+    RGBA16(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::samples16"))] Vec<u16>),
+    /// Grayscale packed into 1, 2 or 4 bits per sample, with the leftmost
+    /// pixel in the high bits and each row padded to a whole byte.
+    K1(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>),
+    K2(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>),
+    K4(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>),
+    /// Palette indices, packed like `K1`, `K2` and `K4` below 8 bits.
+    I1(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>, Vec<PaletteEntry>),
+    I2(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>, Vec<PaletteEntry>),
+    I4(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>, Vec<PaletteEntry>),
+    I8(#[cfg_attr(feature="serde-serialization", serde(with = "::serde_pixels::bytes"))] Vec<u8>, Vec<PaletteEntry>),
+}
+
+#[cfg_attr(feature="serde-serialization", derive(Deserialize, Serialize))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PaletteEntry {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    /// Written to a `tRNS` chunk unless every entry is opaque.
+    pub alpha: u8,
+}
+
+impl PixelsByColorType {
+    /// Returns the bit depth, PNG color type and number of samples per pixel.
+    fn layout(&self) -> (c_int, c_int, usize) {
+        match *self {
+            PixelsByColorType::K8(_) => (8, ffi::COLOR_TYPE_GRAY, 1),
+            PixelsByColorType::KA8(_) => (8, ffi::COLOR_TYPE_GA, 2),
+            PixelsByColorType::RGB8(_) => (8, ffi::COLOR_TYPE_RGB, 3),
+            PixelsByColorType::RGBA8(_) => (8, ffi::COLOR_TYPE_RGBA, 4),
+            PixelsByColorType::BGR8(_) => (8, ffi::COLOR_TYPE_RGB, 3),
+            PixelsByColorType::BGRA8(_) |
+            PixelsByColorType::ARGB8(_) => (8, ffi::COLOR_TYPE_RGBA, 4),
+            PixelsByColorType::K16(_) => (16, ffi::COLOR_TYPE_GRAY, 1),
+            PixelsByColorType::KA16(_) => (16, ffi::COLOR_TYPE_GA, 2),
+            PixelsByColorType::RGB16(_) => (16, ffi::COLOR_TYPE_RGB, 3),
+            PixelsByColorType::RGBA16(_) => (16, ffi::COLOR_TYPE_RGBA, 4),
+            PixelsByColorType::K1(_) => (1, ffi::COLOR_TYPE_GRAY, 1),
+            PixelsByColorType::K2(_) => (2, ffi::COLOR_TYPE_GRAY, 1),
+            PixelsByColorType::K4(_) => (4, ffi::COLOR_TYPE_GRAY, 1),
+            PixelsByColorType::I1(..) => (1, ffi::COLOR_TYPE_PALETTE, 1),
+            PixelsByColorType::I2(..) => (2, ffi::COLOR_TYPE_PALETTE, 1),
+            PixelsByColorType::I4(..) => (4, ffi::COLOR_TYPE_PALETTE, 1),
+            PixelsByColorType::I8(..) => (8, ffi::COLOR_TYPE_PALETTE, 1),
+        }
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut u8 {
+        match *self {
+            PixelsByColorType::K8(ref mut pixels) |
+            PixelsByColorType::KA8(ref mut pixels) |
+            PixelsByColorType::RGB8(ref mut pixels) |
+            PixelsByColorType::RGBA8(ref mut pixels) |
+            PixelsByColorType::BGR8(ref mut pixels) |
+            PixelsByColorType::BGRA8(ref mut pixels) |
+            PixelsByColorType::ARGB8(ref mut pixels) |
+            PixelsByColorType::K1(ref mut pixels) |
+            PixelsByColorType::K2(ref mut pixels) |
+            PixelsByColorType::K4(ref mut pixels) |
+            PixelsByColorType::I1(ref mut pixels, _) |
+            PixelsByColorType::I2(ref mut pixels, _) |
+            PixelsByColorType::I4(ref mut pixels, _) |
+            PixelsByColorType::I8(ref mut pixels, _) => pixels.as_mut_ptr(),
+            PixelsByColorType::K16(ref mut pixels) |
+            PixelsByColorType::KA16(ref mut pixels) |
+            PixelsByColorType::RGB16(ref mut pixels) |
+            PixelsByColorType::RGBA16(ref mut pixels) => pixels.as_mut_ptr() as *mut u8,
+        }
+    }
+
+    /// The pixels as bytes, with 16-bit samples in native byte order.
+    fn as_bytes(&self) -> &[u8] {
+        match *self {
+            PixelsByColorType::K8(ref pixels) |
+            PixelsByColorType::KA8(ref pixels) |
+            PixelsByColorType::RGB8(ref pixels) |
+            PixelsByColorType::RGBA8(ref pixels) |
+            PixelsByColorType::BGR8(ref pixels) |
+            PixelsByColorType::BGRA8(ref pixels) |
+            PixelsByColorType::ARGB8(ref pixels) |
+            PixelsByColorType::K1(ref pixels) |
+            PixelsByColorType::K2(ref pixels) |
+            PixelsByColorType::K4(ref pixels) |
+            PixelsByColorType::I1(ref pixels, _) |
+            PixelsByColorType::I2(ref pixels, _) |
+            PixelsByColorType::I4(ref pixels, _) |
+            PixelsByColorType::I8(ref pixels, _) => pixels,
+            PixelsByColorType::K16(ref pixels) |
+            PixelsByColorType::KA16(ref pixels) |
+            PixelsByColorType::RGB16(ref pixels) |
+            PixelsByColorType::RGBA16(ref pixels) => unsafe {
+                slice::from_raw_parts(pixels.as_ptr() as *const u8, pixels.len() * 2)
+            },
+        }
+    }
+
+    /// The layout of the pixels, unless they are packed or palette indices.
+    fn format(&self) -> Option<PixelFormat> {
+        Some(match *self {
+            PixelsByColorType::K8(_) => PixelFormat::K8,
+            PixelsByColorType::KA8(_) => PixelFormat::KA8,
+            PixelsByColorType::RGB8(_) => PixelFormat::RGB8,
+            PixelsByColorType::RGBA8(_) => PixelFormat::RGBA8,
+            PixelsByColorType::BGR8(_) => PixelFormat::BGR8,
+            PixelsByColorType::BGRA8(_) => PixelFormat::BGRA8,
+            PixelsByColorType::ARGB8(_) => PixelFormat::ARGB8,
+            PixelsByColorType::K16(_) => PixelFormat::K16,
+            PixelsByColorType::KA16(_) => PixelFormat::KA16,
+            PixelsByColorType::RGB16(_) => PixelFormat::RGB16,
+            PixelsByColorType::RGBA16(_) => PixelFormat::RGBA16,
+            _ => return None,
+        })
+    }
+
+    fn byte_len(&self) -> usize {
+        match *self {
+            PixelsByColorType::K8(ref pixels) |
+            PixelsByColorType::KA8(ref pixels) |
+            PixelsByColorType::RGB8(ref pixels) |
+            PixelsByColorType::RGBA8(ref pixels) |
+            PixelsByColorType::BGR8(ref pixels) |
+            PixelsByColorType::BGRA8(ref pixels) |
+            PixelsByColorType::ARGB8(ref pixels) |
+            PixelsByColorType::K1(ref pixels) |
+            PixelsByColorType::K2(ref pixels) |
+            PixelsByColorType::K4(ref pixels) |
+            PixelsByColorType::I1(ref pixels, _) |
+            PixelsByColorType::I2(ref pixels, _) |
+            PixelsByColorType::I4(ref pixels, _) |
+            PixelsByColorType::I8(ref pixels, _) => pixels.len(),
+            PixelsByColorType::K16(ref pixels) |
+            PixelsByColorType::KA16(ref pixels) |
+            PixelsByColorType::RGB16(ref pixels) |
+            PixelsByColorType::RGBA16(ref pixels) => pixels.len() * 2,
+        }
+    }
+
+    fn palette(&self) -> Option<&[PaletteEntry]> {
+        match *self {
+            PixelsByColorType::I1(_, ref palette) |
+            PixelsByColorType::I2(_, ref palette) |
+            PixelsByColorType::I4(_, ref palette) |
+            PixelsByColorType::I8(_, ref palette) => Some(palette),
+            _ => None,
+        }
+    }
+
+    /// Returns grayscale samples or palette indices one per byte, at their
+    /// stored depth. Gray levels are not scaled.
+    fn unpacked_samples(&self, width: u32, height: u32) -> Option<Vec<u8>> {
+        let (bit_depth, _, _) = self.layout();
+        match *self {
+            PixelsByColorType::K8(ref pixels) |
+            PixelsByColorType::I8(ref pixels, _) => Some(pixels.clone()),
+            PixelsByColorType::K1(ref pixels) |
+            PixelsByColorType::K2(ref pixels) |
+            PixelsByColorType::K4(ref pixels) |
+            PixelsByColorType::I1(ref pixels, _) |
+            PixelsByColorType::I2(ref pixels, _) |
+            PixelsByColorType::I4(ref pixels, _) => {
+                Some(pack::unpack(pixels, width as usize, height as usize, bit_depth as u8))
+            }
+            _ => None,
+        }
+    }
+
+    /// Converts grayscale or palette pixels to one sample per byte at the
+    /// requested depth. Returns `None` if the pixels can be written as is.
+    /// `levels` are further gray values, such as the background, that have
+    /// to be representable at the new depth.
+    fn to_bit_depth(&self, width: u32, height: u32, bit_depth: BitDepth, levels: &[u16])
+                    -> Result<Option<(u8, Vec<u8>)>, String> {
+        let (native_depth, color_type, _) = self.layout();
+        let samples = match (bit_depth, self.unpacked_samples(width, height)) {
+            (BitDepth::Native, _) => return Ok(None),
+            (BitDepth::Exactly(depth), None) if depth as c_int == native_depth => return Ok(None),
+            (BitDepth::Exactly(_), None) => {
+                return Err("only grayscale and palette images can change bit depth".to_string());
+            }
+            (BitDepth::Smallest, None) => return Ok(None),
+            (_, Some(samples)) => samples,
+        };
+
+        if color_type == ffi::COLOR_TYPE_PALETTE {
+            let entries = samples.iter().map(|&index| index as usize + 1).max().unwrap_or(0);
+            let needed = pack::smallest_index_depth(cmp::max(entries, self.palette().unwrap().len()));
+            let depth = match bit_depth {
+                BitDepth::Exactly(depth) if depth < needed => {
+                    return Err("palette does not fit in the requested bit depth".to_string());
+                }
+                BitDepth::Exactly(depth) => depth,
+                _ => needed,
+            };
+            return Ok(Some((depth, samples)));
+        }
+
+        // Scale gray levels to 8 bits so that all depths can be compared.
+        let samples = if native_depth < 8 {
+            let step = pack::gray_step(native_depth as u8);
+            samples.iter().map(|&sample| sample * step).collect()
+        } else {
+            samples
+        };
+        let levels: Vec<u8> = levels.iter().filter(|&&level| level as c_int >> native_depth == 0)
+                                    .map(|&level| scale_gray(level, native_depth as u8, 8) as u8).collect();
+        let depth = match bit_depth {
+            BitDepth::Exactly(depth) => depth,
+            _ => cmp::max(pack::smallest_gray_depth(&samples), pack::smallest_gray_depth(&levels)),
+        };
+        if depth == 8 {
+            return Ok(Some((8, samples)));
+        }
+        let step = pack::gray_step(depth);
+        if samples.iter().chain(levels.iter()).any(|&sample| sample % step != 0) {
+            return Err("samples do not fit in the requested bit depth".to_string());
+        }
+        Ok(Some((depth, samples.iter().map(|&sample| sample / step).collect())))
+    }
+
+    fn orient(&mut self, width: &mut u32, height: &mut u32, orientation: Orientation) {
+        let (bit_depth, _, channels) = self.layout();
+        let (w, h) = (*width as usize, *height as usize);
+        match *self {
+            PixelsByColorType::K8(ref mut pixels) |
+            PixelsByColorType::KA8(ref mut pixels) |
+            PixelsByColorType::RGB8(ref mut pixels) |
+            PixelsByColorType::RGBA8(ref mut pixels) |
+            PixelsByColorType::BGR8(ref mut pixels) |
+            PixelsByColorType::BGRA8(ref mut pixels) |
+            PixelsByColorType::ARGB8(ref mut pixels) |
+            PixelsByColorType::I8(ref mut pixels, _) => {
+                exif::orient(pixels, width, height, channels, orientation)
+            }
+            PixelsByColorType::K16(ref mut pixels) |
+            PixelsByColorType::KA16(ref mut pixels) |
+            PixelsByColorType::RGB16(ref mut pixels) |
+            PixelsByColorType::RGBA16(ref mut pixels) => {
+                exif::orient(pixels, width, height, channels, orientation)
+            }
+            PixelsByColorType::K1(ref mut pixels) |
+            PixelsByColorType::K2(ref mut pixels) |
+            PixelsByColorType::K4(ref mut pixels) |
+            PixelsByColorType::I1(ref mut pixels, _) |
+            PixelsByColorType::I2(ref mut pixels, _) |
+            PixelsByColorType::I4(ref mut pixels, _) => {
+                let mut samples = pack::unpack(pixels, w, h, bit_depth as u8);
+                exif::orient(&mut samples, width, height, 1, orientation);
+                *pixels = pack::pack(&samples, *width as usize, *height as usize, bit_depth as u8);
+            }
+        }
+    }
+}
+
+/// Rescales a gray level between bit depths of at most 8, assuming it is
+/// representable at both.
+fn scale_gray(level: u16, from: u8, to: u8) -> u16 {
+    let level = if from < 8 { level * pack::gray_step(from) as u16 } else { level };
+    if to < 8 { level / pack::gray_step(to) as u16 } else { level }
+}
+
+/// Ancillary information read from or written to a PNG alongside the pixels.
+#[cfg_attr(feature="serde-serialization", derive(Deserialize, Serialize))]
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Metadata {
+    pub exif: Option<Exif>,
+    pub time: Option<PngTime>,
+    pub background: Option<Background>,
+    pub significant_bits: Option<SignificantBits>,
+    /// Whether a color image converted to gray while decoding had pixels
+    /// that were not gray. Only set by the decoder: it is ignored when
+    /// encoding and is not serialized.
+    #[cfg_attr(feature="serde-serialization", serde(skip))]
+    pub had_color: bool,
+}
+
+/// The number of significant bits per channel from an `sBIT` chunk, for
+/// the channels of the pixels rather than of the file they were decoded
+/// from. Channels the color type does not have are ignored.
+#[cfg_attr(feature="serde-serialization", derive(Deserialize, Serialize))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct SignificantBits {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub gray: u8,
+    pub alpha: u8,
+}
+
+impl SignificantBits {
+    fn to_ffi(self) -> ffi::png_color_8 {
+        ffi::png_color_8 {
+            red: self.red,
+            green: self.green,
+            blue: self.blue,
+            gray: self.gray,
+            alpha: self.alpha,
+        }
+    }
+
+    fn is_valid_for(&self, bit_depth: c_int, color_type: c_int) -> bool {
+        // Palette entries always have 8-bit samples.
+        let bit_depth = if color_type == ffi::COLOR_TYPE_PALETTE { 8 } else { bit_depth };
+        let valid = |bits: u8| bits >= 1 && bits as c_int <= bit_depth;
+        let color = if color_type & ffi::COLOR_MASK_COLOR != 0 {
+            valid(self.red) && valid(self.green) && valid(self.blue)
+        } else {
+            valid(self.gray)
+        };
+        color && (color_type & ffi::COLOR_MASK_ALPHA == 0 || valid(self.alpha))
+    }
 }
 
+/// The preferred background color from a `bKGD` chunk, in the color type
+/// and bit depth of the pixels rather than of the file they were decoded
+/// from. `Index` is only valid for palette images.
 #[cfg_attr(feature="serde-serialization", derive(Deserialize, Serialize))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Background {
+    Gray(u16),
+    Rgb(u16, u16, u16),
+    /// An index into the palette.
+    Index(u8),
+}
+
+/// The color transparent pixels are composited onto when flattening.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Composite {
+    /// Use the `bKGD` color of the file, or the given color if it has none.
+    FileBackground([u8; 3]),
+    /// Use the given color, ignoring any `bKGD` chunk.
+    Color([u8; 3]),
+}
+
+/// Weights for converting color to gray. Blue gets the remainder of 1.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum GrayWeights {
+    /// 0.2126 red, 0.7152 green and 0.0722 blue, as for sRGB.
+    #[default]
+    Rec709,
+    Custom { red: f64, green: f64 },
+}
+
+impl GrayWeights {
+    /// Returns the red and green weights in libpng's fixed point format.
+    fn to_fixed(self) -> Result<(ffi::png_fixed_point, ffi::png_fixed_point), String> {
+        let (red, green) = match self {
+            GrayWeights::Rec709 => (0.2126, 0.7152),
+            GrayWeights::Custom { red, green } => (red, green),
+        };
+        if !(red >= 0.0 && green >= 0.0 && red + green <= 1.0) {
+            return Err("gray weights must be positive and add up to at most 1".to_string());
+        }
+        let fixed = |weight: f64| (weight * ffi::FP_1 as f64).round() as ffi::png_fixed_point;
+        Ok((fixed(red), fixed(green)))
+    }
+}
+
+/// A decoded image. Images own all their data and are `Send` and `Sync`,
+/// so they can be decoded on one thread and used on another.
+#[cfg_attr(feature="serde-serialization", derive(Deserialize, Serialize))]
+#[derive(Clone, PartialEq, Debug)]
 pub struct Image {
     pub width: u32,
     pub height: u32,
     pub pixels: PixelsByColorType,
+    pub metadata: Metadata,
+}
+
+/// The layout of decoded pixels. 16-bit samples are in native byte order.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    K8,
+    KA8,
+    RGB8,
+    RGBA8,
+    BGR8,
+    BGRA8,
+    ARGB8,
+    K16,
+    KA16,
+    RGB16,
+    RGBA16,
+}
+
+impl PixelFormat {
+    pub fn channels(self) -> usize {
+        match self {
+            PixelFormat::K8 | PixelFormat::K16 => 1,
+            PixelFormat::KA8 | PixelFormat::KA16 => 2,
+            PixelFormat::RGB8 | PixelFormat::BGR8 | PixelFormat::RGB16 => 3,
+            PixelFormat::RGBA8 | PixelFormat::BGRA8 | PixelFormat::ARGB8 | PixelFormat::RGBA16 => 4,
+        }
+    }
+
+    pub fn bit_depth(self) -> usize {
+        match self {
+            PixelFormat::K16 | PixelFormat::KA16 | PixelFormat::RGB16 | PixelFormat::RGBA16 => 16,
+            _ => 8,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        self.channels() * self.bit_depth() / 8
+    }
+
+    pub fn has_alpha(self) -> bool {
+        self.channels().is_multiple_of(2)
+    }
+
+    pub fn is_color(self) -> bool {
+        self.channels() >= 3
+    }
+
+    /// Zeroed pixels of this format for `pixels` pixels.
+    fn allocate(self, pixels: usize) -> PixelsByColorType {
+        let len = pixels * self.channels();
+        match self {
+            PixelFormat::K8 => PixelsByColorType::K8(vec![0; len]),
+            PixelFormat::KA8 => PixelsByColorType::KA8(vec![0; len]),
+            PixelFormat::RGB8 => PixelsByColorType::RGB8(vec![0; len]),
+            PixelFormat::RGBA8 => PixelsByColorType::RGBA8(vec![0; len]),
+            PixelFormat::BGR8 => PixelsByColorType::BGR8(vec![0; len]),
+            PixelFormat::BGRA8 => PixelsByColorType::BGRA8(vec![0; len]),
+            PixelFormat::ARGB8 => PixelsByColorType::ARGB8(vec![0; len]),
+            PixelFormat::K16 => PixelsByColorType::K16(vec![0; len]),
+            PixelFormat::KA16 => PixelsByColorType::KA16(vec![0; len]),
+            PixelFormat::RGB16 => PixelsByColorType::RGB16(vec![0; len]),
+            PixelFormat::RGBA16 => PixelsByColorType::RGBA16(vec![0; len]),
+        }
+    }
+}
+
+impl Image {
+    /// The number of samples per pixel, counting a palette index as one.
+    pub fn channels(&self) -> usize {
+        self.pixels.layout().2
+    }
+
+    /// The size of a pixel in bytes, or `None` for packed gray and palette
+    /// layouts with less than a byte per pixel.
+    pub fn bytes_per_pixel(&self) -> Option<usize> {
+        let (bit_depth, _, channels) = self.pixels.layout();
+        if bit_depth < 8 { None } else { Some(channels * bit_depth as usize / 8) }
+    }
+
+    /// The bytes of row `y`, with 16-bit samples in native byte order and
+    /// packed rows padded to a whole byte. Panics if `y` is out of range.
+    pub fn row(&self, y: u32) -> &[u8] {
+        assert!(y < self.height, "row {} out of range", y);
+        let (bit_depth, _, channels) = self.pixels.layout();
+        let row_bytes = (self.width as usize * channels * bit_depth as usize).div_ceil(8);
+        &self.pixels.as_bytes()[y as usize * row_bytes..(y as usize + 1) * row_bytes]
+    }
+
+    /// Converts the pixels to `format` with the default options. Panics if
+    /// the pixels do not match the dimensions or a palette index is out of
+    /// range; use `convert_with_options` to get an error instead.
+    pub fn convert(&self, format: PixelFormat) -> Image {
+        match self.convert_with_options(format, &ConvertOptions::default()) {
+            Ok(img) => img,
+            Err(e) => panic!("cannot convert image: {}", e),
+        }
+    }
+
+    /// Converts the pixels to `format`, adding opaque alpha or compositing
+    /// onto `options.background` as needed. 16-bit samples are rounded to
+    /// the nearest 8-bit value. The background and significant bits in the
+    /// metadata follow the new format.
+    ///
+    /// Packed gray (`K1`, `K2`, `K4`) and palette images can be converted
+    /// from but not to, since `PixelFormat` has no such layouts. To write
+    /// packed gray, encode with `EncodeOptions::bit_depth`; palette images
+    /// come from `quantize::quantize`.
+    pub fn convert_with_options(&self, format: PixelFormat, options: &ConvertOptions) -> Result<Image, String> {
+        convert::convert(self, format, options)
+    }
+
+    /// Borrows the pixels as an `ImageRef`, or returns `None` for packed
+    /// gray and palette images, which have no `PixelFormat`.
+    pub fn as_ref(&self) -> Option<ImageRef<'_>> {
+        let format = self.pixels.format()?;
+        Some(ImageRef::new(self.width, self.height, format, self.pixels.as_bytes()))
+    }
+}
+
+/// Borrowed pixels that can be encoded without copying them into an
+/// `Image`, such as a framebuffer. Rows start `stride` bytes apart, so a
+/// view may also cover a sub-rectangle of a larger image.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ImageRef<'a> {
+    pub width: u32,
+    pub height: u32,
+    /// The distance in bytes from the start of one row to the next.
+    pub stride: usize,
+    pub format: PixelFormat,
+    /// Only the first `width` pixels of each row are read, so the last row
+    /// may end right after its last pixel.
+    pub data: &'a [u8],
+}
+
+impl<'a> ImageRef<'a> {
+    /// A view of rows without padding between them.
+    pub fn new(width: u32, height: u32, format: PixelFormat, data: &'a [u8]) -> ImageRef<'a> {
+        ImageRef {
+            width,
+            height,
+            stride: width as usize * format.bytes_per_pixel(),
+            format,
+            data,
+        }
+    }
+
+    /// The `width` by `height` pixels with the top-left corner at `x`, `y`.
+    pub fn sub_image(&self, x: u32, y: u32, width: u32, height: u32) -> Result<ImageRef<'a>, String> {
+        if x.checked_add(width).is_none_or(|right| right > self.width) ||
+           y.checked_add(height).is_none_or(|bottom| bottom > self.height) {
+            return Err("rectangle is outside the image".to_string());
+        }
+        let offset = (y as usize).checked_mul(self.stride)
+                                 .and_then(|start| start.checked_add(x as usize * self.format.bytes_per_pixel()));
+        match offset.and_then(|offset| self.data.get(offset..)) {
+            Some(data) => Ok(ImageRef { width, height, data, ..*self }),
+            None => Err("pixel data does not match the image size".to_string()),
+        }
+    }
+
+    /// Checks that the rows are within `data`.
+    fn check(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err("image dimensions must not be zero".to_string());
+        }
+        let row_bytes = (self.width as usize).checked_mul(self.format.bytes_per_pixel());
+        if row_bytes.is_none_or(|row_bytes| row_bytes > self.stride) {
+            return Err("stride is smaller than a row".to_string());
+        }
+        let len = (self.height as usize - 1).checked_mul(self.stride)
+                                            .and_then(|start| start.checked_add(row_bytes.unwrap()));
+        if len.is_none_or(|len| len > self.data.len()) {
+            return Err("pixel data does not match the image size".to_string());
+        }
+        Ok(())
+    }
+
+    /// Copies the rows into an image, assuming they have been checked.
+    fn to_image(self) -> Image {
+        let row_bytes = self.width as usize * self.format.bytes_per_pixel();
+        let mut bytes = Vec::with_capacity(row_bytes * self.height as usize);
+        for row in self.data.chunks(self.stride).take(self.height as usize) {
+            bytes.extend_from_slice(&row[..row_bytes]);
+        }
+        let mut pixels = self.format.allocate(self.width as usize * self.height as usize);
+        match pixels {
+            PixelsByColorType::K16(ref mut samples) |
+            PixelsByColorType::KA16(ref mut samples) |
+            PixelsByColorType::RGB16(ref mut samples) |
+            PixelsByColorType::RGBA16(ref mut samples) => {
+                for (sample, bytes) in samples.iter_mut().zip(bytes.chunks(2)) {
+                    *sample = u16::from_ne_bytes([bytes[0], bytes[1]]);
+                }
+            }
+            PixelsByColorType::K8(ref mut samples) |
+            PixelsByColorType::KA8(ref mut samples) |
+            PixelsByColorType::RGB8(ref mut samples) |
+            PixelsByColorType::RGBA8(ref mut samples) |
+            PixelsByColorType::BGR8(ref mut samples) |
+            PixelsByColorType::BGRA8(ref mut samples) |
+            PixelsByColorType::ARGB8(ref mut samples) => *samples = bytes,
+            _ => unreachable!(),
+        }
+        Image { width: self.width, height: self.height, pixels, metadata: Metadata::default() }
+    }
+}
+
+/// The pixels the encode functions accept: an `Image`, or an `ImageRef` to
+/// write borrowed pixels without copying them.
+#[derive(Clone, Copy, Debug)]
+pub enum EncodeSource<'a> {
+    Image(&'a Image),
+    Ref(ImageRef<'a>),
+}
+
+impl<'a> EncodeSource<'a> {
+    unsafe fn encode(self,
+                     options: &EncodeOptions,
+                     io_ptr: *mut c_void,
+                     write_fn: extern "C" fn(*mut ffi::png_struct, *mut u8, size_t),
+                     flush_fn: extern "C" fn(*mut ffi::png_struct)) -> Result<(), String> {
+        match self {
+            EncodeSource::Image(img) => encode(img, options, io_ptr, write_fn, flush_fn),
+            EncodeSource::Ref(ref img) => encode_ref(img, options, io_ptr, write_fn, flush_fn),
+        }
+    }
+}
+
+impl<'a> From<&'a Image> for EncodeSource<'a> {
+    fn from(img: &'a Image) -> EncodeSource<'a> {
+        EncodeSource::Image(img)
+    }
+}
+
+impl<'a> From<&'a mut Image> for EncodeSource<'a> {
+    fn from(img: &'a mut Image) -> EncodeSource<'a> {
+        EncodeSource::Image(img)
+    }
+}
+
+impl<'a> From<ImageRef<'a>> for EncodeSource<'a> {
+    fn from(img: ImageRef<'a>) -> EncodeSource<'a> {
+        EncodeSource::Ref(img)
+    }
+}
+
+/// Transformations applied while decoding.
+#[derive(Clone, Default)]
+pub struct DecodeOptions {
+    /// Rotate and flip the image as requested by the EXIF orientation tag.
+    /// The tag is then reset to `Orientation::Normal`.
+    pub auto_orient: bool,
+    /// Composite the image onto a background color, yielding `RGB8` pixels,
+    /// or `K8` together with `to_gray`.
+    pub composite: Option<Composite>,
+    /// Convert color images to gray with the given weights, yielding `KA8`
+    /// pixels, or `K8`, `K16` and `KA16` with the other options. Files with
+    /// a `gAMA` chunk are converted in linear light. Whether the image had
+    /// any color is reported in `metadata.had_color`.
+    pub to_gray: Option<GrayWeights>,
+    /// Decode to this layout instead of the one chosen by `composite` and
+    /// `keep_16_bit`, which still apply. Compositing requires a layout
+    /// without alpha.
+    pub format: Option<PixelFormat>,
+    /// Return 16-bit images as `K16`, `KA16`, `RGB16` or `RGBA16` rather
+    /// than reducing them to 8 bits.
+    pub keep_16_bit: bool,
+    /// Shift samples down so that only the significant bits given by `sBIT`
+    /// remain, e.g. yielding values up to 4095 for 12-bit data.
+    pub shift_to_significant_bits: bool,
+}
+
+/// Settings applied while encoding.
+#[derive(Clone, Debug, Default)]
+pub struct EncodeOptions {
+    /// Shift samples up from the precision given by
+    /// `metadata.significant_bits` to the full bit depth of the image.
+    pub shift_from_significant_bits: bool,
+    pub bit_depth: BitDepth,
+    /// Write the image in the smallest color type and bit depth that
+    /// decodes to the same pixels: drop opaque alpha channels, store
+    /// grayscale as gray, use a palette for up to 256 colors and a `tRNS`
+    /// color for a single transparent one. `bit_depth` still applies when
+    /// given exactly. Ignored when shifting from significant bits.
+    pub reduce: bool,
+    pub filter: Filter,
+    /// zlib compression level from 0 to 9, or libpng's default.
+    pub compression_level: Option<u8>,
+    pub strategy: Strategy,
+    /// Filter and compress the image data on this many threads. With 0 or
+    /// 1, libpng compresses the whole image on the calling thread.
+    pub threads: usize,
+}
+
+/// The row filters libpng may use.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Filter {
+    /// libpng's choice: no filtering for palette and low bit depth images,
+    /// adaptive filtering otherwise.
+    #[default]
+    Default,
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+    /// Choose a filter for each row with libpng's heuristic.
+    Adaptive,
+}
+
+impl Filter {
+    /// The filters for `png_set_filter`, unless libpng should choose.
+    fn flags(self) -> Option<c_int> {
+        match self {
+            Filter::Default => None,
+            Filter::None => Some(ffi::FILTER_FLAG_NONE),
+            Filter::Sub => Some(ffi::FILTER_FLAG_SUB),
+            Filter::Up => Some(ffi::FILTER_FLAG_UP),
+            Filter::Average => Some(ffi::FILTER_FLAG_AVG),
+            Filter::Paeth => Some(ffi::FILTER_FLAG_PAETH),
+            Filter::Adaptive => Some(ffi::ALL_FILTERS),
+        }
+    }
+}
+
+/// The zlib compression strategy.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Strategy {
+    /// libpng's choice, depending on whether the rows are filtered.
+    #[default]
+    Default,
+    /// `Z_DEFAULT_STRATEGY`
+    Normal,
+    Filtered,
+    HuffmanOnly,
+    Rle,
+    Fixed,
+}
+
+impl Strategy {
+    /// The zlib strategy, unless libpng should choose.
+    fn zlib_strategy(self) -> Option<c_int> {
+        match self {
+            Strategy::Default => None,
+            Strategy::Normal => Some(ffi::Z_DEFAULT_STRATEGY),
+            Strategy::Filtered => Some(ffi::Z_FILTERED),
+            Strategy::HuffmanOnly => Some(ffi::Z_HUFFMAN_ONLY),
+            Strategy::Rle => Some(ffi::Z_RLE),
+            Strategy::Fixed => Some(ffi::Z_FIXED),
+        }
+    }
+}
+
+/// The bit depth grayscale and palette images are written at.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BitDepth {
+    /// The depth of the pixel format.
+    #[default]
+    Native,
+    /// 1, 2, 4 or 8 bits. Encoding fails if a gray level or palette index
+    /// cannot be represented exactly.
+    Exactly(u8),
+    /// The smallest depth that represents every sample exactly.
+    Smallest,
 }
 
 // This intermediate data structure is used to read
@@ -54,16 +811,25 @@ struct ImageData<'a> {
 }
 
 pub fn is_png(image: &[u8]) -> bool {
+    if image.len() < 8 {
+        return false;
+    }
     unsafe {
-        ffi::RUST_png_sig_cmp(image.as_ptr(), 0, 8) == 0
+        ffi::png_sig_cmp(image.as_ptr(), 0, 8) == 0
     }
 }
 
 pub extern fn read_data(png_ptr: *mut ffi::png_struct, data: *mut u8, length: size_t) {
     unsafe {
-        let io_ptr = ffi::RUST_png_get_io_ptr(png_ptr);
+        let io_ptr = ffi::png_get_io_ptr(png_ptr);
         let image_data: &mut ImageData = mem::transmute(io_ptr);
         let len = length as usize;
+        if image_data.data.len() - image_data.offset < len {
+            // Nothing on this frame needs dropping, so libpng may jump out
+            // of it. Returning short would make it read stale bytes, up to
+            // the length of a corrupt chunk.
+            ffi::png_error(png_ptr, b"unexpected end of data\0".as_ptr() as *const c_char);
+        }
         let buf = slice::from_raw_parts_mut(data, len);
         let end_pos = std::cmp::min(image_data.data.len()-image_data.offset, len);
         let src = &image_data.data[image_data.offset..image_data.offset+end_pos];
@@ -73,6 +839,10 @@ pub extern fn read_data(png_ptr: *mut ffi::png_struct, data: *mut u8, length: si
 }
 
 pub fn load_png<P: AsRef<Path>>(path: P) -> Result<Image, String> {
+    load_png_with_options(path, &DecodeOptions::default())
+}
+
+pub fn load_png_with_options<P: AsRef<Path>>(path: P, options: &DecodeOptions) -> Result<Image, String> {
     let mut reader = match File::open(path) {
         Ok(r) => r,
         Err(e) => return Err(format!("could not open file: {}", e.description())),
@@ -82,101 +852,327 @@ pub fn load_png<P: AsRef<Path>>(path: P) -> Result<Image, String> {
         Ok(_) => (),
         Err(e) => return Err(format!("could not read file: {}", e.description())),
     }
-    load_png_from_memory(&buffer)
+    load_png_from_memory_with_options(&buffer, options)
 }
 
 pub fn load_png_from_memory(image: &[u8]) -> Result<Image, String> {
-    unsafe {
-        let mut png_ptr = ffi::RUST_png_create_read_struct(&*ffi::RUST_png_get_header_ver(ptr::null_mut()),
-                                                      ptr::null_mut(),
-                                                      ptr::null_mut(),
-                                                      ptr::null_mut());
-        if png_ptr.is_null() {
-            return Err("could not create read struct".to_string());
-        }
-        let mut info_ptr = ffi::RUST_png_create_info_struct(png_ptr);
-        if info_ptr.is_null() {
-            ffi::RUST_png_destroy_read_struct(&mut png_ptr, ptr::null_mut(), ptr::null_mut());
-            return Err("could not create info struct".to_string());
-        }
-        let res = ffi::setjmp(ffi::pngshim_jmpbuf(png_ptr));
-        if res != 0 {
-            ffi::RUST_png_destroy_read_struct(&mut png_ptr, &mut info_ptr, ptr::null_mut());
-            return Err("error reading png".to_string());
-        }
-
-        let mut image_data = ImageData {
-            data: image,
-            offset: 0,
-        };
+    load_png_from_memory_with_options(image, &DecodeOptions::default())
+}
 
-        ffi::RUST_png_set_read_fn(png_ptr, mem::transmute(&mut image_data), read_data);
-        ffi::RUST_png_read_info(png_ptr, info_ptr);
+pub fn load_png_from_memory_with_options(image: &[u8], options: &DecodeOptions) -> Result<Image, String> {
+    let mut img = decode(image, options)?;
 
-        let width = ffi::RUST_png_get_image_width(png_ptr, info_ptr) as usize;
-        let height = ffi::RUST_png_get_image_height(png_ptr, info_ptr) as usize;
-        let color_type = ffi::RUST_png_get_color_type(png_ptr, info_ptr);
-        let bit_depth = ffi::RUST_png_get_bit_depth(png_ptr, info_ptr);
+    // libpng 1.6.16 predates eXIf, so look for it ourselves.
+    let exif = chunks::chunks_unchecked(image).ok().and_then(|mut chunks| {
+        chunks.find(|chunk| match *chunk {
+            Ok(ref chunk) => &chunk.chunk_type == b"eXIf",
+            Err(_) => true,
+        })
+    });
+    if let Some(Ok(chunk)) = exif {
+        img.metadata.exif = Some(Exif::new(chunk.data.to_vec()));
+    }
 
-        // convert palette and grayscale to rgb
-        match color_type as c_int {
-            ffi::COLOR_TYPE_PALETTE => {
-                ffi::RUST_png_set_palette_to_rgb(png_ptr);
+    if options.auto_orient {
+        let orientation = img.metadata.exif.as_ref().and_then(|exif| exif.orientation());
+        if let Some(orientation) = orientation {
+            img.pixels.orient(&mut img.width, &mut img.height, orientation);
+            if let Some(ref mut exif) = img.metadata.exif {
+                exif.set_orientation(Orientation::Normal);
             }
-            ffi::COLOR_TYPE_GRAY | ffi::COLOR_TYPE_GRAY_ALPHA => {
-                ffi::RUST_png_set_gray_to_rgb(png_ptr);
-            }
-            _ => {}
         }
+    }
 
-        // convert 16-bit channels to 8-bit
-        if bit_depth == 16 {
-            ffi::RUST_png_set_strip_16(png_ptr);
-        }
+    Ok(img)
+}
 
-        // add alpha channels
-        ffi::RUST_png_set_add_alpha(png_ptr, 0xff, ffi::FILLER_AFTER);
-        if ffi::RUST_png_get_valid(png_ptr, info_ptr, ffi::INFO_tRNS as u32) != 0 {
-            ffi::RUST_png_set_tRNS_to_alpha(png_ptr);
+fn decode(image: &[u8], options: &DecodeOptions) -> Result<Image, String> {
+    let mut pixels = None;
+    let (width, height, metadata) = unsafe {
+        read_png(image, options, None, |width, height, format| {
+            let mut buf = format.allocate(width as usize * height as usize);
+            let row_bytes = width as usize * format.bytes_per_pixel();
+            let image_buf = buf.as_mut_ptr();
+            pixels = Some(buf);
+            Ok((0..height as usize).map(|idx| image_buf.add(row_bytes * idx)).collect())
+        })?
+    };
+    Ok(Image {
+        width,
+        height,
+        pixels: pixels.unwrap(),
+        metadata,
+    })
+}
+
+/// Decodes `image` into `dst` as `format`, with rows starting `stride`
+/// bytes apart, for example into a sub-rectangle of a larger buffer.
+/// Bytes between rows are left untouched. Returns the dimensions of the
+/// image, which can be read beforehand with `dimensions`.
+///
+/// Formats without alpha drop any transparency, and gray formats convert
+/// color images with the Rec. 709 weights.
+pub fn decode_into(image: &[u8], dst: &mut [u8], stride: usize, format: PixelFormat)
+                   -> Result<(u32, u32), String> {
+    let (width, height, _) = unsafe {
+        read_png(image, &DecodeOptions::default(), Some(format), |width, height, format| {
+            let row_bytes = width as usize * format.bytes_per_pixel();
+            if stride < row_bytes {
+                return Err("stride is smaller than a row".to_string());
+            }
+            let needed = match height as usize {
+                0 => 0,
+                height => stride.checked_mul(height - 1).and_then(|len| len.checked_add(row_bytes))
+                                .ok_or_else(|| "image is too large".to_string())?,
+            };
+            if dst.len() < needed {
+                return Err(format!("destination needs {} bytes", needed));
+            }
+            let dst = dst.as_mut_ptr();
+            Ok((0..height as usize).map(|idx| dst.add(stride * idx)).collect())
+        })?
+    };
+    Ok((width, height))
+}
+
+/// Returns the width and height from the header of `image`.
+pub fn dimensions(image: &[u8]) -> Result<(u32, u32), String> {
+    match chunks::chunks_unchecked(image)?.next() {
+        Some(Ok(ref chunk)) if &chunk.chunk_type == b"IHDR" && chunk.data.len() == 13 => {
+            let data = chunk.data;
+            Ok((u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+                u32::from_be_bytes([data[4], data[5], data[6], data[7]])))
         }
+        Some(Err(e)) => Err(e),
+        _ => Err("missing IHDR chunk".to_string()),
+    }
+}
+
+/// Reads `image`, transforming the pixels to `format`, or to the format
+/// chosen by `options` if `None`. `rows` is given the dimensions and format
+/// and returns where to store each row.
+unsafe fn read_png<F>(image: &[u8], options: &DecodeOptions, format: Option<PixelFormat>, mut rows: F)
+                      -> Result<(u32, u32, Metadata), String>
+    where F: FnMut(u32, u32, PixelFormat) -> Result<Vec<*mut u8>, String> {
+    let gray_weights = options.to_gray.unwrap_or_default().to_fixed()?;
+    let mut png_ptr = ffi::png_create_read_struct(&*ffi::png_get_header_ver(ptr::null_mut()),
+                                                  ptr::null_mut(),
+                                                  ptr::null_mut(),
+                                                  ptr::null_mut());
+    if png_ptr.is_null() {
+        return Err("could not create read struct".to_string());
+    }
+    let mut info_ptr = ffi::png_create_info_struct(png_ptr);
+    if info_ptr.is_null() {
+        ffi::png_destroy_read_struct(&mut png_ptr, ptr::null_mut(), ptr::null_mut());
+        return Err("could not create info struct".to_string());
+    }
+    let res = ffi::setjmp(ffi::pngshim_jmpbuf(png_ptr));
+    if res != 0 {
+        ffi::png_destroy_read_struct(&mut png_ptr, &mut info_ptr, ptr::null_mut());
+        return Err("error reading png".to_string());
+    }
+
+    let mut image_data = ImageData {
+        data: image,
+        offset: 0,
+    };
 
-        ffi::RUST_png_set_packing(png_ptr);
-        ffi::RUST_png_set_interlace_handling(png_ptr);
-        ffi::RUST_png_read_update_info(png_ptr, info_ptr);
+    ffi::png_set_read_fn(png_ptr, mem::transmute(&mut image_data), read_data);
+    ffi::png_read_info(png_ptr, info_ptr);
 
-        let updated_bit_depth = ffi::RUST_png_get_bit_depth(png_ptr, info_ptr);
-        let updated_color_type = ffi::RUST_png_get_color_type(png_ptr, info_ptr);
+    let width = ffi::png_get_image_width(png_ptr, info_ptr);
+    let height = ffi::png_get_image_height(png_ptr, info_ptr);
+    let color_type = ffi::png_get_color_type(png_ptr, info_ptr) as c_int;
+    let bit_depth = ffi::png_get_bit_depth(png_ptr, info_ptr);
 
-        let (color_type, pixel_width) = match (updated_color_type as c_int, updated_bit_depth) {
-            (ffi::COLOR_TYPE_RGB, 8) |
-            (ffi::COLOR_TYPE_RGBA, 8) |
-            (ffi::COLOR_TYPE_PALETTE, 8) => (PixelsByColorType::RGBA8 as fn(Vec<u8>) -> PixelsByColorType, 4usize),
-            (ffi::COLOR_TYPE_GRAY, 8) => (PixelsByColorType::K8 as fn(Vec<u8>) -> PixelsByColorType, 1usize),
-            (ffi::COLOR_TYPE_GA, 8) => (PixelsByColorType::KA8 as fn(Vec<u8>) -> PixelsByColorType, 2usize),
-            _ => panic!("color type not supported"),
-        };
+    let mut metadata = Metadata::default();
+    let mut background = ptr::null_mut();
+    let file_background = if ffi::png_get_bKGD(png_ptr, info_ptr, &mut background) != 0 {
+        let background = &*background;
+        Some(match color_type {
+            ffi::COLOR_TYPE_PALETTE => Background::Index(background.index),
+            ffi::COLOR_TYPE_GRAY | ffi::COLOR_TYPE_GRAY_ALPHA => Background::Gray(background.gray),
+            _ => Background::Rgb(background.red, background.green, background.blue),
+        })
+    } else {
+        None
+    };
+
+    let mut sig_bit = ptr::null_mut();
+    let file_sig_bit = if ffi::png_get_sBIT(png_ptr, info_ptr, &mut sig_bit) != 0 {
+        let sig_bit = &*sig_bit;
+        if options.shift_to_significant_bits {
+            ffi::png_set_shift(png_ptr, sig_bit);
+        }
+        // libpng fills in the channels the file does not have.
+        let color = color_type & ffi::COLOR_MASK_COLOR != 0;
+        let alpha = color_type & ffi::COLOR_MASK_ALPHA != 0;
+        Some(SignificantBits {
+            red: if color { sig_bit.red } else { 0 },
+            green: if color { sig_bit.green } else { 0 },
+            blue: if color { sig_bit.blue } else { 0 },
+            gray: if color { 0 } else { sig_bit.gray },
+            alpha: if alpha { sig_bit.alpha } else { 0 },
+        })
+    } else {
+        None
+    };
 
-        let mut image_data: Vec<u8> = vec![0u8;width * height * pixel_width];
-        let image_buf = image_data.as_mut_ptr();
-        let mut row_pointers: Vec<*mut u8> = (0..height).map(|idx| {
-            image_buf.offset((width * pixel_width * idx) as isize)
+    // By default, decode to RGBA at 8 bits, or RGB when compositing.
+    let keep_16_bit = bit_depth == 16 && options.keep_16_bit;
+    let format = format.or(options.format).unwrap_or(match (options.to_gray.is_some(), options.composite.is_some(), keep_16_bit) {
+        (false, false, false) => PixelFormat::RGBA8,
+        (false, false, true) => PixelFormat::RGBA16,
+        (false, true, false) => PixelFormat::RGB8,
+        (false, true, true) => PixelFormat::RGB16,
+        (true, false, false) => PixelFormat::KA8,
+        (true, false, true) => PixelFormat::KA16,
+        (true, true, false) => PixelFormat::K8,
+        (true, true, true) => PixelFormat::K16,
+    });
+    let has_trns = ffi::png_get_valid(png_ptr, info_ptr, ffi::INFO_tRNS as u32) != 0;
+
+    // bKGD and sBIT describe the file, so carry them over to the layout of
+    // the decoded pixels, which is what the encoder expects. libpng strips
+    // 16-bit samples to their high byte.
+    let mut palette = Vec::new();
+    let mut colors = ptr::null_mut();
+    let mut num_colors = 0;
+    if ffi::png_get_PLTE(png_ptr, info_ptr, &mut colors, &mut num_colors) != 0 {
+        palette = slice::from_raw_parts(colors, num_colors as usize).iter().map(|color| {
+            PaletteEntry { red: color.red, green: color.green, blue: color.blue, alpha: 0xff }
         }).collect();
+    }
+    let is_palette = color_type == ffi::COLOR_TYPE_PALETTE;
+    let file_palette = if is_palette { Some(&palette[..]) } else { None };
+    metadata.background = file_background.and_then(|color| {
+        convert::convert_background(color, bit_depth as c_int, file_palette, format, gray_weights,
+                                    |sample| (sample >> 8) as u8)
+    });
+    // Palette entries always have 8-bit samples, as does their tRNS alpha.
+    metadata.significant_bits = file_sig_bit.map(|bits| {
+        convert::convert_significant_bits(bits, if is_palette { 8 } else { bit_depth },
+                                          color_type & ffi::COLOR_MASK_COLOR != 0,
+                                          color_type & ffi::COLOR_MASK_ALPHA != 0 || has_trns, format)
+    });
 
-        ffi::RUST_png_read_image(png_ptr, row_pointers.as_mut_ptr());
+    // expand palettes and low bit depth grayscale to 8 bits
+    if color_type == ffi::COLOR_TYPE_PALETTE {
+        ffi::png_set_palette_to_rgb(png_ptr);
+    } else if bit_depth < 8 {
+        ffi::png_set_expand_gray_1_2_4_to_8(png_ptr);
+    }
+    if has_trns && (format.has_alpha() || options.composite.is_some()) {
+        ffi::png_set_tRNS_to_alpha(png_ptr);
+    }
 
-        ffi::RUST_png_destroy_read_struct(&mut png_ptr, &mut info_ptr, ptr::null_mut());
+    match (bit_depth, format.bit_depth()) {
+        // convert 16-bit channels to 8-bit
+        (16, 8) => ffi::png_set_strip_16(png_ptr),
+        (16, _) => {}
+        (_, 16) => ffi::png_set_expand_16(png_ptr),
+        _ => {}
+    }
+    if format.bit_depth() == 16 && cfg!(target_endian = "little") {
+        ffi::png_set_swap(png_ptr);
+    }
 
-        Ok(Image {
-            width: width as u32,
-            height: height as u32,
-            pixels: color_type(image_data),
-        })
+    let is_color = color_type & ffi::COLOR_MASK_COLOR != 0;
+    if format.is_color() && !is_color {
+        ffi::png_set_gray_to_rgb(png_ptr);
+    } else if !format.is_color() && is_color {
+        ffi::png_set_rgb_to_gray_fixed(png_ptr, ffi::ERROR_ACTION_NONE, gray_weights.0, gray_weights.1);
     }
+
+    match options.composite {
+        Some(Composite::FileBackground(_)) if !background.is_null() => {
+            ffi::png_set_background_fixed(png_ptr, background, ffi::BACKGROUND_GAMMA_FILE,
+                                               1, ffi::FP_1);
+        }
+        Some(Composite::FileBackground(color)) | Some(Composite::Color(color)) => {
+            // The color is given in the output format, so no expansion is needed.
+            let color = ffi::png_color_16 {
+                index: 0,
+                red: color[0] as u16,
+                green: color[1] as u16,
+                blue: color[2] as u16,
+                gray: color[0] as u16,
+            };
+            ffi::png_set_background_fixed(png_ptr, &color, ffi::BACKGROUND_GAMMA_SCREEN,
+                                               0, ffi::FP_1);
+        }
+        None if format.has_alpha() => {
+            // add alpha channels, opaque at both 8 and 16 bits. libpng does
+            // not swap added channels, so ARGB needs the filler in front.
+            let location = if format == PixelFormat::ARGB8 { ffi::FILLER_BEFORE } else { ffi::FILLER_AFTER };
+            ffi::png_set_add_alpha(png_ptr, 0xffff, location);
+        }
+        // Expanding palettes and to 16 bits also turns tRNS into alpha.
+        None if color_type & ffi::COLOR_MASK_ALPHA != 0 || has_trns => {
+            ffi::png_set_strip_alpha(png_ptr);
+        }
+        None => {}
+    }
+
+    match format {
+        PixelFormat::BGR8 | PixelFormat::BGRA8 => ffi::png_set_bgr(png_ptr),
+        PixelFormat::ARGB8 => ffi::png_set_swap_alpha(png_ptr),
+        _ => {}
+    }
+
+    ffi::png_set_packing(png_ptr);
+    ffi::png_set_interlace_handling(png_ptr);
+    ffi::png_read_update_info(png_ptr, info_ptr);
+
+    let updated_bit_depth = ffi::png_get_bit_depth(png_ptr, info_ptr) as usize;
+    let updated_color_type = ffi::png_get_color_type(png_ptr, info_ptr) as c_int;
+    let channels = match updated_color_type {
+        ffi::COLOR_TYPE_GRAY => 1,
+        ffi::COLOR_TYPE_GA => 2,
+        ffi::COLOR_TYPE_RGB => 3,
+        ffi::COLOR_TYPE_RGBA => 4,
+        _ => 0,
+    };
+    if channels != format.channels() || updated_bit_depth != format.bit_depth() {
+        ffi::png_destroy_read_struct(&mut png_ptr, &mut info_ptr, ptr::null_mut());
+        return Err("cannot convert the image to the requested format".to_string());
+    }
+
+    let mut row_pointers = match rows(width, height, format) {
+        Ok(row_pointers) => row_pointers,
+        Err(e) => {
+            ffi::png_destroy_read_struct(&mut png_ptr, &mut info_ptr, ptr::null_mut());
+            return Err(e);
+        }
+    };
+
+    // Errors from here on return through the shim, so that the rows are
+    // freed.
+    if ffi::pngshim_read_image(png_ptr, row_pointers.as_mut_ptr()) == 0 {
+        ffi::png_destroy_read_struct(&mut png_ptr, &mut info_ptr, ptr::null_mut());
+        return Err("error reading png".to_string());
+    }
+    metadata.had_color = ffi::png_get_rgb_to_gray_status(png_ptr) != 0;
+
+    // tIME may follow the image data, so read up to IEND. The pixels are
+    // complete by now, so a broken trailer only loses the time, which may
+    // be what was cut off.
+    if ffi::pngshim_read_end(png_ptr, info_ptr) != 0 {
+        let mut time = ptr::null_mut();
+        if ffi::png_get_tIME(png_ptr, info_ptr, &mut time) != 0 {
+            metadata.time = Some(PngTime::from_ffi(&*time));
+        }
+    }
+
+    ffi::png_destroy_read_struct(&mut png_ptr, &mut info_ptr, ptr::null_mut());
+
+    Ok((width, height, metadata))
 }
 
 pub extern fn write_data(png_ptr: *mut ffi::png_struct, data: *mut u8, length: size_t) {
     unsafe {
-        let io_ptr = ffi::RUST_png_get_io_ptr(png_ptr);
+        let io_ptr = ffi::png_get_io_ptr(png_ptr);
         let writer: &mut &mut Write = mem::transmute(io_ptr);
         let buf = slice::from_raw_parts(data as *const _, length as usize);
         match writer.write_all(buf) {
@@ -188,7 +1184,7 @@ pub extern fn write_data(png_ptr: *mut ffi::png_struct, data: *mut u8, length: s
 
 pub extern fn flush_data(png_ptr: *mut ffi::png_struct) {
     unsafe {
-        let io_ptr = ffi::RUST_png_get_io_ptr(png_ptr);
+        let io_ptr = ffi::png_get_io_ptr(png_ptr);
         let writer: &mut &mut Write = mem::transmute(io_ptr);
         match writer.flush() {
             Err(e) => panic!("{}", e.description()),
@@ -197,63 +1193,36 @@ pub extern fn flush_data(png_ptr: *mut ffi::png_struct) {
     }
 }
 
-pub fn store_png<P: AsRef<Path>>(img: &mut Image, path: P) -> Result<(),String> {
+pub fn store_png<'a, I: Into<EncodeSource<'a>>, P: AsRef<Path>>(img: I, path: P) -> Result<(),String> {
+    store_png_with_options(img, path, &EncodeOptions::default())
+}
+
+pub fn store_png_with_options<'a, I, P>(img: I, path: P, options: &EncodeOptions) -> Result<(),String>
+    where I: Into<EncodeSource<'a>>, P: AsRef<Path> {
+    let img = img.into();
     let mut file = match File::create(path) {
         Ok(f) => f,
         Err(e) => return Err(format!("{}", e))
     };
 
-    let mut writer = &mut file as &mut Write;
+    if options.threads > 1 {
+        let data = to_vec_with_options(img, options)?;
+        return file.write_all(&data).map_err(|e| format!("{}", e));
+    }
+
+    let mut writer = &mut file as &mut dyn Write;
 
     // Box it again because a &Trait is too big to fit in a void*.
     let writer = &mut writer;
 
     unsafe {
-        let mut png_ptr = ffi::RUST_png_create_write_struct(&*ffi::RUST_png_get_header_ver(ptr::null_mut()),
-                                                       ptr::null_mut(),
-                                                       ptr::null_mut(),
-                                                       ptr::null_mut());
-        if png_ptr.is_null() {
-            return Err("could not create write struct".to_string());
-        }
-        let mut info_ptr = ffi::RUST_png_create_info_struct(png_ptr);
-        if info_ptr.is_null() {
-            ffi::RUST_png_destroy_write_struct(&mut png_ptr, ptr::null_mut());
-            return Err("could not create info struct".to_string());
-        }
-        let res = ffi::setjmp(ffi::pngshim_jmpbuf(png_ptr));
-        if res != 0 {
-            ffi::RUST_png_destroy_write_struct(&mut png_ptr, &mut info_ptr);
-            return Err("error writing png".to_string());
-        }
-
-        ffi::RUST_png_set_write_fn(png_ptr, mem::transmute(writer), write_data, flush_data);
-
-        let (bit_depth, color_type, pixel_width, image_buf) = match img.pixels {
-            PixelsByColorType::RGB8(ref mut pixels) => (8, ffi::COLOR_TYPE_RGB, 3, pixels.as_mut_ptr()),
-            PixelsByColorType::RGBA8(ref mut pixels) => (8, ffi::COLOR_TYPE_RGBA, 4, pixels.as_mut_ptr()),
-            PixelsByColorType::K8(ref mut pixels) => (8, ffi::COLOR_TYPE_GRAY, 1, pixels.as_mut_ptr()),
-            PixelsByColorType::KA8(ref mut pixels) => (8, ffi::COLOR_TYPE_GA, 2, pixels.as_mut_ptr()),
-        };
-
-        ffi::RUST_png_set_IHDR(png_ptr, info_ptr, img.width, img.height, bit_depth, color_type,
-                          ffi::INTERLACE_NONE, ffi::COMPRESSION_TYPE_DEFAULT, ffi::FILTER_NONE);
-
-        let mut row_pointers: Vec<*mut u8> = (0..img.height as usize).map(|idx| {
-            image_buf.offset((((img.width * pixel_width) as usize) * idx) as isize)
-        }).collect();
-        ffi::RUST_png_set_rows(png_ptr, info_ptr, row_pointers.as_mut_ptr());
-
-        ffi::RUST_png_write_png(png_ptr, info_ptr, ffi::TRANSFORM_IDENTITY, ptr::null_mut());
-
-        ffi::RUST_png_destroy_write_struct(&mut png_ptr, &mut info_ptr);
+        img.encode(options, writer as *mut &mut dyn Write as *mut c_void, write_data, flush_data)
     }
-    Ok(())
 }
 
 pub extern fn write_data_to_buf(png_ptr: *mut ffi::png_struct, data: *mut u8, length: size_t) {
     unsafe {
-        let io_ptr = ffi::RUST_png_get_io_ptr(png_ptr);
+        let io_ptr = ffi::png_get_io_ptr(png_ptr);
         let dest: &mut Vec<u8> = mem::transmute(io_ptr);
         dest.reserve_exact(length as usize);
         let buf = slice::from_raw_parts(data as *const _, length as usize);
@@ -265,53 +1234,348 @@ pub extern fn write_data_to_buf(png_ptr: *mut ffi::png_struct, data: *mut u8, le
 
 pub extern fn flush_data_to_buf(_png_ptr: *mut ffi::png_struct) {}
 
-//TODO: This should share most of the implementation with store_png
-pub fn to_vec(img: &mut Image) -> Result<Vec<u8>,String> {
-    let target: Vec<u8> = Vec::new();
+pub fn to_vec<'a, I: Into<EncodeSource<'a>>>(img: I) -> Result<Vec<u8>,String> {
+    to_vec_with_options(img, &EncodeOptions::default())
+}
+
+pub fn to_vec_with_options<'a, I: Into<EncodeSource<'a>>>(img: I, options: &EncodeOptions)
+                                                         -> Result<Vec<u8>,String> {
+    let img = img.into();
+    if options.threads > 1 {
+        // Let libpng write everything but the compressed data.
+        let stored = EncodeOptions {
+            filter: Filter::None,
+            compression_level: Some(0),
+            threads: 0,
+            ..options.clone()
+        };
+        let stored = to_vec_with_options(img, &stored)?;
+        return parallel::recompress(&stored, options);
+    }
+
+    let mut target: Vec<u8> = Vec::new();
 
     unsafe {
-        let mut png_ptr = ffi::RUST_png_create_write_struct(&*ffi::RUST_png_get_header_ver(ptr::null_mut()),
-                                                       ptr::null_mut(),
-                                                       ptr::null_mut(),
-                                                       ptr::null_mut());
-        if png_ptr.is_null() {
-            return Err("could not create write struct".to_string());
-        }
-        let mut info_ptr = ffi::RUST_png_create_info_struct(png_ptr);
-        if info_ptr.is_null() {
-            ffi::RUST_png_destroy_write_struct(&mut png_ptr, ptr::null_mut());
-            return Err("could not create info struct".to_string());
-        }
-        let res = ffi::setjmp(ffi::pngshim_jmpbuf(png_ptr));
-        if res != 0 {
-            ffi::RUST_png_destroy_write_struct(&mut png_ptr, &mut info_ptr);
-            return Err("error writing png".to_string());
-        }
-
-        ffi::RUST_png_set_write_fn(png_ptr, mem::transmute(&target),
-                                   write_data_to_buf, flush_data_to_buf);
-
-        let (bit_depth, color_type, pixel_width, image_buf) = match img.pixels {
-            PixelsByColorType::RGB8(ref mut pixels) => (8, ffi::COLOR_TYPE_RGB, 3, pixels.as_mut_ptr()),
-            PixelsByColorType::RGBA8(ref mut pixels) => (8, ffi::COLOR_TYPE_RGBA, 4, pixels.as_mut_ptr()),
-            PixelsByColorType::K8(ref mut pixels) => (8, ffi::COLOR_TYPE_GRAY, 1, pixels.as_mut_ptr()),
-            PixelsByColorType::KA8(ref mut pixels) => (8, ffi::COLOR_TYPE_GA, 2, pixels.as_mut_ptr()),
+        img.encode(options, &mut target as *mut Vec<u8> as *mut c_void, write_data_to_buf, flush_data_to_buf)?;
+    }
+
+    Ok(target)
+}
+
+/// Everything libpng is given to write an image, checked against the
+/// color type and bit depth.
+struct Frame<'a> {
+    width: u32,
+    height: u32,
+    bit_depth: c_int,
+    color_type: c_int,
+    /// The layout of the rows, which gives the channel order of 8-bit color.
+    format: Option<PixelFormat>,
+    rows: Vec<*mut u8>,
+    /// Colors and alpha values of the palette entries.
+    palette: Option<(Vec<ffi::png_color>, Vec<u8>)>,
+    exif: Option<&'a Exif>,
+    time: Option<PngTime>,
+    background: Option<ffi::png_color_16>,
+    transparent: Option<ffi::png_color_16>,
+    significant_bits: Option<SignificantBits>,
+    /// Whether the rows hold one sample per byte below 8 bits.
+    pack: bool,
+}
+
+unsafe fn encode(img: &Image,
+                 options: &EncodeOptions,
+                 io_ptr: *mut c_void,
+                 write_fn: extern "C" fn(*mut ffi::png_struct, *mut u8, size_t),
+                 flush_fn: extern "C" fn(*mut ffi::png_struct)) -> Result<(), String> {
+    // Later steps index the pixels by the dimensions.
+    let (native_depth, _, channels) = img.pixels.layout();
+    let native_len = (img.width as usize).checked_mul(channels * native_depth as usize)
+                                         .map(|bits| bits.div_ceil(8))
+                                         .and_then(|row_bytes| row_bytes.checked_mul(img.height as usize));
+    if img.width == 0 || img.height == 0 {
+        return Err("image dimensions must not be zero".to_string());
+    }
+    if native_len != Some(img.pixels.byte_len()) {
+        return Err("pixel data does not match the image size".to_string());
+    }
+
+    let reduce = options.reduce && !options.shift_from_significant_bits;
+    let reduced = if reduce { reduce::reduce(img) } else { None };
+    let requested_depth = match options.bit_depth {
+        BitDepth::Native if reduce => BitDepth::Smallest,
+        bit_depth => bit_depth,
+    };
+    let (pixels, metadata, transparent) = match reduced {
+        Some(ref reduced) => (&reduced.pixels, &reduced.metadata, reduced.transparent),
+        None => (&img.pixels, &img.metadata, None),
+    };
+    let (native_depth, color_type, channels) = pixels.layout();
+
+    match options.bit_depth {
+        BitDepth::Exactly(1) | BitDepth::Exactly(2) | BitDepth::Exactly(4) | BitDepth::Exactly(8) => {}
+        BitDepth::Exactly(_) if color_type == ffi::COLOR_TYPE_PALETTE || color_type == ffi::COLOR_TYPE_GRAY => {
+            return Err("grayscale and palette images can only have 1, 2, 4 or 8 bits".to_string());
+        }
+        _ => {}
+    }
+    let levels: Vec<u16> = [metadata.background, transparent].iter().filter_map(|color| match *color {
+        Some(Background::Gray(gray)) => Some(gray),
+        _ => None,
+    }).collect();
+    let converted = pixels.to_bit_depth(img.width, img.height, requested_depth, &levels)?;
+    let bit_depth = match converted {
+        Some((depth, _)) => depth as c_int,
+        None => native_depth,
+    };
+    // Gray levels outside the pixel data follow the change of depth.
+    let rescale = |color: Option<Background>| match color {
+        Some(Background::Gray(gray)) if bit_depth != native_depth && gray as c_int >> native_depth == 0 => {
+            Some(Background::Gray(scale_gray(gray, native_depth as u8, bit_depth as u8)))
+        }
+        color => color,
+    };
+    let background = rescale(metadata.background);
+    let transparent = rescale(transparent);
+    let significant_bits = match metadata.significant_bits {
+        // Reduced images may have fewer bits than the original precision.
+        Some(sig_bit) if reduce && color_type == ffi::COLOR_TYPE_GRAY => {
+            Some(SignificantBits { gray: cmp::min(sig_bit.gray, bit_depth as u8), ..sig_bit })
+        }
+        sig_bit => sig_bit,
+    };
+
+    if let Some(palette) = pixels.palette() {
+        if palette.is_empty() || palette.len() > 1 << bit_depth {
+            return Err("palette size does not match the bit depth".to_string());
+        }
+        let samples = match converted {
+            Some((_, ref samples)) => samples.clone(),
+            None => pixels.unpacked_samples(img.width, img.height).unwrap(),
         };
+        if samples.iter().any(|&index| index as usize >= palette.len()) {
+            return Err("palette index out of range".to_string());
+        }
+    }
 
-        ffi::RUST_png_set_IHDR(png_ptr, info_ptr, img.width, img.height, bit_depth, color_type,
-                          ffi::INTERLACE_NONE, ffi::COMPRESSION_TYPE_DEFAULT, ffi::FILTER_NONE);
+    let row_bytes = match converted {
+        // libpng packs these rows itself.
+        Some(_) => img.width as usize,
+        None => (img.width as usize * channels * bit_depth as usize).div_ceil(8),
+    };
+    let expected_len = row_bytes * img.height as usize;
+    let actual_len = match converted {
+        Some((_, ref samples)) => samples.len(),
+        None => pixels.byte_len(),
+    };
+    if actual_len != expected_len {
+        return Err("pixel data does not match the image size".to_string());
+    }
 
-        let mut row_pointers: Vec<*mut u8> = (0..img.height as usize).map(|idx| {
-            image_buf.offset((((img.width * pixel_width) as usize) * idx) as isize)
-        }).collect();
-        ffi::RUST_png_set_rows(png_ptr, info_ptr, row_pointers.as_mut_ptr());
+    if let Some(time) = metadata.time {
+        if !time.is_valid() {
+            return Err("invalid modification time".to_string());
+        }
+    }
+    if let Some(ref sig_bit) = significant_bits {
+        if !sig_bit.is_valid_for(bit_depth, color_type) {
+            return Err("significant bits do not match the color type".to_string());
+        }
+    }
 
-        ffi::RUST_png_write_png(png_ptr, info_ptr, ffi::TRANSFORM_IDENTITY, ptr::null_mut());
+    let background = match background {
+        Some(background) => Some(match (background, color_type) {
+            (Background::Gray(gray), ffi::COLOR_TYPE_GRAY) |
+            (Background::Gray(gray), ffi::COLOR_TYPE_GA) if gray as u32 >> bit_depth == 0 => {
+                ffi::png_color_16 { index: 0, red: 0, green: 0, blue: 0, gray }
+            }
+            (Background::Rgb(red, green, blue), ffi::COLOR_TYPE_RGB) |
+            (Background::Rgb(red, green, blue), ffi::COLOR_TYPE_RGBA)
+                if (red | green | blue) as u32 >> bit_depth == 0 => {
+                ffi::png_color_16 { index: 0, red, green, blue, gray: 0 }
+            }
+            (Background::Index(index), ffi::COLOR_TYPE_PALETTE)
+                if (index as usize) < pixels.palette().unwrap().len() => {
+                ffi::png_color_16 { index, red: 0, green: 0, blue: 0, gray: 0 }
+            }
+            _ => return Err("background does not match the color type".to_string()),
+        }),
+        None => None,
+    };
+    let transparent = transparent.map(|transparent| match transparent {
+        Background::Gray(gray) => ffi::png_color_16 { index: 0, red: 0, green: 0, blue: 0, gray },
+        Background::Rgb(red, green, blue) => ffi::png_color_16 { index: 0, red, green, blue, gray: 0 },
+        Background::Index(_) => unreachable!(),
+    });
+
+    // libpng only reads the rows, through pointers that are not const.
+    let image_buf = match converted {
+        Some((_, ref samples)) => samples.as_ptr(),
+        None => pixels.as_bytes().as_ptr(),
+    } as *mut u8;
+    let frame = Frame {
+        width: img.width,
+        height: img.height,
+        bit_depth,
+        color_type,
+        format: pixels.format(),
+        rows: (0..img.height as usize).map(|idx| image_buf.add(row_bytes * idx)).collect(),
+        palette: pixels.palette().map(|palette| {
+            let colors = palette.iter().map(|entry| {
+                ffi::png_color { red: entry.red, green: entry.green, blue: entry.blue }
+            }).collect();
+            (colors, palette.iter().map(|entry| entry.alpha).collect())
+        }),
+        exif: metadata.exif.as_ref(),
+        time: metadata.time,
+        background,
+        transparent,
+        significant_bits,
+        pack: converted.is_some() && bit_depth < 8,
+    };
+    write_png(frame, options, io_ptr, write_fn, flush_fn)
+}
+
+/// Writes `img` without copying the rows, unless the bit depth may change.
+unsafe fn encode_ref(img: &ImageRef,
+                     options: &EncodeOptions,
+                     io_ptr: *mut c_void,
+                     write_fn: extern "C" fn(*mut ffi::png_struct, *mut u8, size_t),
+                     flush_fn: extern "C" fn(*mut ffi::png_struct)) -> Result<(), String> {
+    img.check()?;
+    if options.reduce || options.bit_depth != BitDepth::Native {
+        return encode(&img.to_image(), options, io_ptr, write_fn, flush_fn);
+    }
+    let color_type = match img.format.channels() {
+        1 => ffi::COLOR_TYPE_GRAY,
+        2 => ffi::COLOR_TYPE_GA,
+        3 => ffi::COLOR_TYPE_RGB,
+        _ => ffi::COLOR_TYPE_RGBA,
+    };
+    let data = img.data.as_ptr() as *mut u8;
+    let frame = Frame {
+        width: img.width,
+        height: img.height,
+        bit_depth: img.format.bit_depth() as c_int,
+        color_type,
+        format: Some(img.format),
+        rows: (0..img.height as usize).map(|idx| data.add(img.stride * idx)).collect(),
+        palette: None,
+        exif: None,
+        time: None,
+        background: None,
+        transparent: None,
+        significant_bits: None,
+        pack: false,
+    };
+    write_png(frame, options, io_ptr, write_fn, flush_fn)
+}
 
-        ffi::RUST_png_destroy_write_struct(&mut png_ptr, &mut info_ptr);
+unsafe fn write_png(mut frame: Frame,
+                    options: &EncodeOptions,
+                    io_ptr: *mut c_void,
+                    write_fn: extern "C" fn(*mut ffi::png_struct, *mut u8, size_t),
+                    flush_fn: extern "C" fn(*mut ffi::png_struct)) -> Result<(), String> {
+    if options.compression_level.is_some_and(|level| level > 9) {
+        return Err("compression level must be between 0 and 9".to_string());
+    }
+    if options.shift_from_significant_bits && frame.significant_bits.is_none() {
+        return Err("shifting requires significant bits".to_string());
     }
 
-    Ok(target)
+    // Everything libpng reads from is allocated before setting the jump
+    // buffer, so that nothing leaks when it reports an error.
+    let mut png_ptr = ffi::png_create_write_struct(&*ffi::png_get_header_ver(ptr::null_mut()),
+                                                   ptr::null_mut(),
+                                                   ptr::null_mut(),
+                                                   ptr::null_mut());
+    if png_ptr.is_null() {
+        return Err("could not create write struct".to_string());
+    }
+    let mut info_ptr = ffi::png_create_info_struct(png_ptr);
+    if info_ptr.is_null() {
+        ffi::png_destroy_write_struct(&mut png_ptr, ptr::null_mut());
+        return Err("could not create info struct".to_string());
+    }
+    let res = ffi::setjmp(ffi::pngshim_jmpbuf(png_ptr));
+    if res != 0 {
+        ffi::png_destroy_write_struct(&mut png_ptr, &mut info_ptr);
+        return Err("error writing png".to_string());
+    }
+
+    ffi::png_set_write_fn(png_ptr, io_ptr, write_fn, flush_fn);
+
+    ffi::png_set_IHDR(png_ptr, info_ptr, frame.width, frame.height, frame.bit_depth, frame.color_type,
+                      ffi::INTERLACE_NONE, ffi::COMPRESSION_TYPE_DEFAULT, ffi::FILTER_NONE);
+
+    if let Some(exif) = frame.exif {
+        // libpng copies the chunk data, so it only has to outlive this call.
+        let chunk = ffi::png_unknown_chunk {
+            name: *b"eXIf\0",
+            data: exif.data.as_ptr() as *mut u8,
+            size: exif.data.len() as size_t,
+            location: ffi::HAVE_IHDR,
+        };
+        ffi::png_set_unknown_chunks(png_ptr, info_ptr, &chunk, 1);
+    }
+
+    if let Some(ref background) = frame.background {
+        ffi::png_set_bKGD(png_ptr, info_ptr, background);
+    }
+
+    if let Some((ref colors, ref alpha)) = frame.palette {
+        ffi::png_set_PLTE(png_ptr, info_ptr, colors.as_ptr(), colors.len() as c_int);
+
+        // tRNS only needs to cover the entries up to the last transparent one.
+        if let Some(last) = alpha.iter().rposition(|&alpha| alpha != 0xff) {
+            ffi::png_set_tRNS(png_ptr, info_ptr, alpha.as_ptr(), last as c_int + 1, ptr::null());
+        }
+    }
+
+    if let Some(ref transparent) = frame.transparent {
+        ffi::png_set_tRNS(png_ptr, info_ptr, ptr::null(), 0, transparent);
+    }
+
+    if let Some(sig_bit) = frame.significant_bits {
+        ffi::png_set_sBIT(png_ptr, info_ptr, &sig_bit.to_ffi());
+    }
+
+    if let Some(time) = frame.time {
+        ffi::png_set_tIME(png_ptr, info_ptr, &time.to_ffi());
+    }
+
+    ffi::png_set_rows(png_ptr, info_ptr, frame.rows.as_mut_ptr());
+
+    match frame.format {
+        Some(PixelFormat::BGR8) | Some(PixelFormat::BGRA8) => ffi::png_set_bgr(png_ptr),
+        Some(PixelFormat::ARGB8) => ffi::png_set_swap_alpha(png_ptr),
+        _ => {}
+    }
+
+    if let Some(filters) = options.filter.flags() {
+        ffi::png_set_filter(png_ptr, ffi::FILTER_NONE, filters);
+    }
+    if let Some(level) = options.compression_level {
+        ffi::png_set_compression_level(png_ptr, level as c_int);
+    }
+    if let Some(strategy) = options.strategy.zlib_strategy() {
+        ffi::png_set_compression_strategy(png_ptr, strategy);
+    }
+
+    let mut transforms = ffi::TRANSFORM_IDENTITY;
+    if frame.bit_depth == 16 && cfg!(target_endian = "little") {
+        transforms |= ffi::TRANSFORM_SWAP_ENDIAN;
+    }
+    if options.shift_from_significant_bits {
+        transforms |= ffi::TRANSFORM_SHIFT;
+    }
+    if frame.pack {
+        transforms |= ffi::TRANSFORM_PACKING;
+    }
+    ffi::png_write_png(png_ptr, info_ptr, transforms, ptr::null_mut());
+
+    ffi::png_destroy_write_struct(&mut png_ptr, &mut info_ptr);
+    Ok(())
 }
 
 #[cfg(test)]
@@ -322,8 +1586,11 @@ mod test {
     use std::iter::repeat;
     use std::path::PathBuf;
 
-    use super::{ffi, load_png, store_png, to_vec, Image};
-    use super::PixelsByColorType::{RGB8, RGBA8};
+    use super::{ffi, load_png, load_png_from_memory, load_png_from_memory_with_options, store_png, to_vec};
+    use super::{to_vec_with_options, Background, Composite, DecodeOptions, EncodeOptions, Exif, Image};
+    use super::{BitDepth, Metadata, Orientation, PaletteEntry, PixelFormat, PngTime, SignificantBits};
+    use super::{decode_into, dimensions, load_png_with_options, Filter, GrayWeights, ImageRef};
+    use super::PixelsByColorType::{ARGB8, BGR8, BGRA8, I2, I8, K1, K4, K8, KA8, RGB16, RGB8, RGBA16, RGBA8};
 
     #[test]
     fn test_valid_png() {
@@ -336,7 +1603,7 @@ mod test {
         let count = reader.read(&mut buf[0..1024]).unwrap();
         assert!(count >= 8);
         unsafe {
-            let res = ffi::RUST_png_sig_cmp(buf.as_ptr(), 0, 8);
+            let res = ffi::png_sig_cmp(buf.as_ptr(), 0, 8);
             assert!(res == 0);
         }
     }
@@ -369,58 +1636,13 @@ mod test {
         load_rgba8("test/gray.png", 100, 100);
     }
 
-    // // test::Bencher is unstable in beta, so these are commented out for the time being.
-    //
-    // fn bench_file_from_memory(b: &mut test::Bencher, file: &'static str,
-    //                           w: u32, h: u32, c: &'static str) {
-    //     let mut reader = match File::open(file) {
-    //         Ok(r) => r,
-    //         Err(e) => panic!("could not open '{}': {}", file, e.description())
-    //     };
-    //     let mut buf = vec![];
-    //     match reader.read_to_end(&mut buf) {
-    //         Ok(_) => (),
-    //         Err(e) => panic!(e)
-    //     }
-    //     b.bench_n(1, |b| b.iter(|| {
-    //         match load_png_from_memory(buf.as_slice()) {
-    //             Err(m) => panic!(m),
-    //             Ok(image) => {
-    //                 let color_type = match image.pixels {
-    //                     K8(_) => "K8",
-    //                     KA8(_) => "KA8",
-    //                     RGB8(_) => "RGB8",
-    //                     RGBA8(_) => "RGBA8",
-    //                 };
-    //                 assert_eq!(color_type, c);
-    //                 assert_eq!(image.width, w);
-    //                 assert_eq!(image.height, h);
-    //             }
-    //         }
-    //     }));
-    // }
-    //
-    // #[bench]
-    // fn test_load_perf_screenshot(b: &mut test::Bencher) {
-    //     bench_file_from_memory(b, "test/servo-screenshot.png", 831, 624, "RGBA8");
-    // }
-    //
-    // #[bench]
-    // fn test_load_perf_dino(b: &mut test::Bencher) {
-    //     bench_file_from_memory(b, "test/mozilla-dinosaur-head-logo.png", 1300, 929, "RGBA8");
-    // }
-    //
-    // #[bench]
-    // fn test_load_perf_rust(b: &mut test::Bencher) {
-    //     bench_file_from_memory(b, "test/rust-huge-logo.png", 4000, 4000, "RGBA8");
-    // }
-
     #[test]
     fn test_store() {
         let mut img = Image {
             width: 10,
             height: 10,
             pixels: RGB8(repeat(100).take(10 * 10 * 3).collect()),
+            metadata: Default::default(),
         };
         let res = store_png(&mut img, &PathBuf::from("test/store.png"));
         assert!(res.is_ok());
@@ -432,6 +1654,7 @@ mod test {
             width: 10,
             height: 10,
             pixels: RGB8(repeat(100).take(10 * 10 * 3).collect()),
+            metadata: Default::default(),
         };
         let res = to_vec(&mut img);
         assert!(res.is_ok());
@@ -447,4 +1670,650 @@ mod test {
         File::open(&path).unwrap().read_to_end(&mut expected).unwrap();
         assert_eq!(data, expected);
     }
+
+    #[test]
+    fn test_exif() {
+        // A little-endian TIFF header with a single IFD entry: orientation 6.
+        let exif = Exif::new(b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0\x06\0\0\0\0\0\0\0".to_vec());
+        let mut img = Image {
+            width: 3,
+            height: 1,
+            pixels: K8(vec![1, 2, 3]),
+            metadata: Metadata { exif: Some(exif.clone()), ..Default::default() },
+        };
+        let data = to_vec(&mut img).unwrap();
+
+        let decoded = load_png_from_memory(&data).unwrap();
+        assert_eq!(decoded.metadata.exif, Some(exif));
+        assert_eq!((decoded.width, decoded.height), (3, 1));
+
+        let options = DecodeOptions { auto_orient: true, ..Default::default() };
+        let decoded = load_png_from_memory_with_options(&data, &options).unwrap();
+        assert_eq!((decoded.width, decoded.height), (1, 3));
+        assert_eq!(decoded.metadata.exif.unwrap().orientation(), Some(Orientation::Normal));
+        match decoded.pixels {
+            RGBA8(pixels) => assert_eq!(pixels.iter().step_by(4).cloned().collect::<Vec<u8>>(), vec![1, 2, 3]),
+            _ => panic!("Expected RGBA8"),
+        }
+    }
+
+    #[test]
+    fn test_time() {
+        let time = PngTime { year: 2015, month: 6, day: 1, hour: 12, minute: 30, second: 0 };
+        let mut img = Image {
+            width: 1,
+            height: 1,
+            pixels: K8(vec![0]),
+            metadata: Metadata { time: Some(time), ..Default::default() },
+        };
+        let decoded = load_png_from_memory(&to_vec(&mut img).unwrap()).unwrap();
+        assert_eq!(decoded.metadata.time, Some(time));
+
+        img.metadata.time = Some(PngTime { month: 13, ..time });
+        assert!(to_vec(&mut img).is_err());
+    }
+
+    #[test]
+    fn test_missing_iend() {
+        let mut data = vec![];
+        File::open("test/gray.png").unwrap().read_to_end(&mut data).unwrap();
+        let mut expected = load_png_from_memory(&data).unwrap();
+        assert!(expected.metadata.time.is_some());
+        expected.metadata.time = None;
+        assert_eq!(&data[data.len() - 8..data.len() - 4], b"IEND");
+        for &end in &[data.len() - 12, data.len() - 6] {
+            let truncated = &data[..end];
+            assert_eq!(load_png_from_memory(truncated).unwrap(), expected);
+            let mut dst = vec![0; 100 * 100];
+            assert_eq!(decode_into(truncated, &mut dst, 100, PixelFormat::K8).unwrap(), (100, 100));
+        }
+
+        // The time is dropped with the trailer it may have been part of.
+        let time = PngTime { year: 2015, month: 6, day: 1, hour: 12, minute: 30, second: 0 };
+        let mut img = Image {
+            width: 1,
+            height: 1,
+            pixels: K8(vec![7]),
+            metadata: Metadata { time: Some(time), ..Default::default() },
+        };
+        let data = to_vec(&mut img).unwrap();
+        let decoded = load_png_from_memory(&data[..data.len() - 12]).unwrap();
+        assert_eq!(decoded.pixels, RGBA8(vec![7, 7, 7, 255]));
+        assert_eq!(decoded.metadata.time, None);
+    }
+
+    #[test]
+    fn test_background() {
+        let mut img = Image {
+            width: 2,
+            height: 1,
+            pixels: KA8(vec![100, 255, 0, 0]),
+            metadata: Metadata { background: Some(Background::Gray(200)), ..Default::default() },
+        };
+        let data = to_vec(&mut img).unwrap();
+        // The background follows the decoded pixels, so they encode again.
+        let mut decoded = load_png_from_memory(&data).unwrap();
+        assert_eq!(decoded.metadata.background, Some(Background::Rgb(200, 200, 200)));
+        assert!(to_vec(&mut decoded).is_ok());
+        let options = DecodeOptions { to_gray: Some(GrayWeights::Rec709), keep_16_bit: true, ..Default::default() };
+        assert_eq!(load_png_from_memory_with_options(&data, &options).unwrap().metadata.background,
+                   Some(Background::Gray(200)));
+        let options = DecodeOptions { format: Some(PixelFormat::RGBA16), ..Default::default() };
+        assert_eq!(load_png_from_memory_with_options(&data, &options).unwrap().metadata.background,
+                   Some(Background::Rgb(200 * 257, 200 * 257, 200 * 257)));
+
+        let options = DecodeOptions { composite: Some(Composite::FileBackground([0, 0, 0])), ..Default::default() };
+        match load_png_from_memory_with_options(&data, &options).unwrap().pixels {
+            RGB8(pixels) => assert_eq!(pixels, vec![100, 100, 100, 200, 200, 200]),
+            _ => panic!("Expected RGB8"),
+        }
+
+        let options = DecodeOptions { composite: Some(Composite::Color([10, 20, 30])), ..Default::default() };
+        match load_png_from_memory_with_options(&data, &options).unwrap().pixels {
+            RGB8(pixels) => assert_eq!(pixels, vec![100, 100, 100, 10, 20, 30]),
+            _ => panic!("Expected RGB8"),
+        }
+
+        img.metadata.background = Some(Background::Rgb(1, 2, 3));
+        assert!(to_vec(&mut img).is_err());
+    }
+
+    #[test]
+    fn test_significant_bits() {
+        let sig_bit = SignificantBits { red: 12, green: 12, blue: 12, ..Default::default() };
+        let mut img = Image {
+            width: 2,
+            height: 1,
+            pixels: RGB16(vec![0, 1, 2, 4093, 4094, 4095]),
+            metadata: Metadata { significant_bits: Some(sig_bit), ..Default::default() },
+        };
+        let options = EncodeOptions { shift_from_significant_bits: true, ..Default::default() };
+        let data = to_vec_with_options(&mut img, &options).unwrap();
+
+        let options = DecodeOptions { keep_16_bit: true, ..Default::default() };
+        let decoded = load_png_from_memory_with_options(&data, &options).unwrap();
+        // The added alpha channel is fully significant.
+        assert_eq!(decoded.metadata.significant_bits, Some(SignificantBits { alpha: 16, ..sig_bit }));
+        match decoded.pixels {
+            // libpng replicates the high bits into the low ones when shifting up.
+            RGBA16(pixels) => assert_eq!(&pixels[4..7], &[4093 << 4 | 0xf, 4094 << 4 | 0xf, 0xffff]),
+            _ => panic!("Expected RGBA16"),
+        }
+
+        let options = DecodeOptions { keep_16_bit: true, shift_to_significant_bits: true, ..Default::default() };
+        match load_png_from_memory_with_options(&data, &options).unwrap().pixels {
+            RGBA16(pixels) => assert_eq!(pixels, vec![0, 1, 2, 0xffff, 4093, 4094, 4095, 0xffff]),
+            _ => panic!("Expected RGBA16"),
+        }
+
+        // Stripped to 8 bits, every bit is significant.
+        let mut decoded = load_png_from_memory(&data).unwrap();
+        assert_eq!(decoded.metadata.significant_bits,
+                   Some(SignificantBits { red: 8, green: 8, blue: 8, gray: 0, alpha: 8 }));
+        assert!(to_vec(&mut decoded).is_ok());
+
+        img.metadata.significant_bits = Some(SignificantBits { gray: 12, ..Default::default() });
+        assert!(to_vec(&mut img).is_err());
+
+        // Gray spreads to the color channels of the decoded pixels.
+        let mut img = Image {
+            width: 1,
+            height: 1,
+            pixels: K8(vec![0b10101000]),
+            metadata: Metadata { significant_bits: Some(SignificantBits { gray: 5, ..Default::default() }), ..Default::default() },
+        };
+        let mut decoded = load_png_from_memory(&to_vec(&mut img).unwrap()).unwrap();
+        assert_eq!(decoded.metadata.significant_bits,
+                   Some(SignificantBits { red: 5, green: 5, blue: 5, gray: 0, alpha: 8 }));
+        let mut decoded = load_png_from_memory(&to_vec(&mut decoded).unwrap()).unwrap();
+        assert_eq!(decoded.metadata.significant_bits,
+                   Some(SignificantBits { red: 5, green: 5, blue: 5, gray: 0, alpha: 8 }));
+        assert!(to_vec(&mut decoded).is_ok());
+    }
+
+    fn ihdr_bit_depth(png: &[u8]) -> u8 {
+        // The bit depth follows the signature, chunk header, width and height.
+        png[8 + 8 + 8]
+    }
+
+    #[test]
+    fn test_low_bit_depth() {
+        // A packed 1-bit checkerboard: 10 pixels per row need two bytes.
+        let mut img = Image {
+            width: 10,
+            height: 2,
+            pixels: K1(vec![0b10101010, 0b10000000, 0b01010101, 0b01000000]),
+            metadata: Default::default(),
+        };
+        let data = to_vec(&mut img).unwrap();
+        assert_eq!(ihdr_bit_depth(&data), 1);
+        let decoded = load_png_from_memory(&data).unwrap();
+        match decoded.pixels {
+            RGBA8(pixels) => {
+                let gray: Vec<u8> = pixels.iter().step_by(4).cloned().collect();
+                assert_eq!(&gray[..4], &[255, 0, 255, 0]);
+                assert_eq!(&gray[10..14], &[0, 255, 0, 255]);
+            }
+            _ => panic!("Expected RGBA8"),
+        }
+
+        let mut img = Image {
+            width: 4,
+            height: 1,
+            pixels: K8(vec![0, 255, 255, 0]),
+            metadata: Default::default(),
+        };
+        let options = EncodeOptions { bit_depth: BitDepth::Smallest, ..Default::default() };
+        assert_eq!(ihdr_bit_depth(&to_vec_with_options(&mut img, &options).unwrap()), 1);
+        img.pixels = K8(vec![0, 85, 170, 255]);
+        assert_eq!(ihdr_bit_depth(&to_vec_with_options(&mut img, &options).unwrap()), 2);
+        let options = EncodeOptions { bit_depth: BitDepth::Exactly(1), ..Default::default() };
+        assert!(to_vec_with_options(&mut img, &options).is_err());
+    }
+
+    #[test]
+    fn test_palette() {
+        let palette = vec![
+            PaletteEntry { red: 255, green: 0, blue: 0, alpha: 255 },
+            PaletteEntry { red: 0, green: 0, blue: 255, alpha: 0 },
+        ];
+        let mut img = Image {
+            width: 3,
+            height: 1,
+            pixels: I8(vec![0, 1, 0], palette.clone()),
+            metadata: Default::default(),
+        };
+        let options = EncodeOptions { bit_depth: BitDepth::Smallest, ..Default::default() };
+        let data = to_vec_with_options(&mut img, &options).unwrap();
+        assert_eq!(ihdr_bit_depth(&data), 1);
+        match load_png_from_memory(&data).unwrap().pixels {
+            RGBA8(pixels) => assert_eq!(pixels, vec![255, 0, 0, 255, 0, 0, 255, 0, 255, 0, 0, 255]),
+            _ => panic!("Expected RGBA8"),
+        }
+
+        // Packed 2-bit indices are written as they are.
+        img.pixels = I2(vec![0b00010000], palette.clone());
+        assert_eq!(ihdr_bit_depth(&to_vec(&mut img).unwrap()), 2);
+
+        img.pixels = I8(vec![0, 2, 0], palette);
+        assert!(to_vec(&mut img).is_err());
+    }
+
+    #[test]
+    fn test_truncated_data() {
+        let mut data = vec![];
+        File::open("test/gray.png").unwrap().read_to_end(&mut data).unwrap();
+        // Cuts inside the IDAT, inside its CRC, and between it and IEND.
+        for &end in &[100, data.len() / 2, data.len() - 14, data.len() - 12] {
+            let truncated = &data[..end];
+            let mut dst = vec![0; 100 * 100];
+            let result = decode_into(truncated, &mut dst, 100, PixelFormat::K8);
+            if end == data.len() - 12 {
+                assert!(result.is_ok());
+                assert!(load_png_from_memory(truncated).is_ok());
+            } else {
+                assert!(result.is_err(), "{}", end);
+                assert!(load_png_from_memory(truncated).is_err(), "{}", end);
+            }
+        }
+
+        // A chunk claiming to run past the end of the data.
+        let mut corrupt = data.clone();
+        let idat = corrupt.windows(4).position(|window| window == b"IDAT").unwrap() - 4;
+        corrupt[idat..idat + 4].copy_from_slice(&0x7fff_0000u32.to_be_bytes());
+        assert!(load_png_from_memory(&corrupt).is_err());
+    }
+
+    #[test]
+    fn test_corrupt_image_data() {
+        let mut data = vec![];
+        File::open("test/gray.png").unwrap().read_to_end(&mut data).unwrap();
+        let idat = data.windows(4).position(|window| window == b"IDAT").unwrap() + 4;
+        let len = u32::from_be_bytes([data[idat - 8], data[idat - 7], data[idat - 6], data[idat - 5]]) as usize;
+
+        // A bad CRC, and garbage in the middle of the deflate stream with a
+        // good one. libpng reports both while the rows are allocated.
+        let mut bad_crc = data.clone();
+        bad_crc[idat + len] ^= 1;
+        let mut bad_zlib = data.clone();
+        for byte in &mut bad_zlib[idat + len / 2..idat + len] {
+            *byte = 0xff;
+        }
+        let crc = ::chunks::crc32(b"IDAT", &bad_zlib[idat..idat + len]);
+        bad_zlib[idat + len..idat + len + 4].copy_from_slice(&crc.to_be_bytes());
+
+        for corrupt in &[bad_crc, bad_zlib] {
+            assert!(load_png_from_memory(corrupt).is_err());
+            let options = DecodeOptions { keep_16_bit: true, format: Some(PixelFormat::RGB16), ..Default::default() };
+            assert!(load_png_from_memory_with_options(corrupt, &options).is_err());
+            let mut dst = vec![0; 100 * 100];
+            assert!(decode_into(corrupt, &mut dst, 100, PixelFormat::K8).is_err());
+        }
+    }
+
+    #[test]
+    fn test_trns_without_alpha() {
+        let palette = vec![
+            PaletteEntry { red: 10, green: 20, blue: 30, alpha: 0 },
+            PaletteEntry { red: 40, green: 50, blue: 60, alpha: 255 },
+        ];
+        let mut img = Image { width: 2, height: 1, pixels: I8(vec![0, 1], palette), metadata: Default::default() };
+        for &bit_depth in &[BitDepth::Native, BitDepth::Smallest] {
+            let options = EncodeOptions { bit_depth, ..Default::default() };
+            let data = to_vec_with_options(&mut img, &options).unwrap();
+
+            let decode = |format| {
+                let options = DecodeOptions { format: Some(format), ..Default::default() };
+                load_png_from_memory_with_options(&data, &options).unwrap().pixels
+            };
+            assert_eq!(decode(PixelFormat::RGBA8), RGBA8(vec![10, 20, 30, 0, 40, 50, 60, 255]));
+            assert_eq!(decode(PixelFormat::RGB8), RGB8(vec![10, 20, 30, 40, 50, 60]));
+            // Expanding to 16 bits would otherwise add an alpha channel.
+            assert_eq!(decode(PixelFormat::RGB16),
+                       RGB16(vec![10 * 257, 20 * 257, 30 * 257, 40 * 257, 50 * 257, 60 * 257]));
+        }
+    }
+
+    /// Encodes RGBA8 pixels with reduction and checks that they decode
+    /// unchanged. Returns the PNG.
+    fn reduce_round_trip(width: u32, height: u32, pixels: Vec<u8>) -> Vec<u8> {
+        let mut img = Image { width, height, pixels: RGBA8(pixels.clone()), metadata: Default::default() };
+        let options = EncodeOptions { reduce: true, ..Default::default() };
+        let data = to_vec_with_options(&mut img, &options).unwrap();
+        match load_png_from_memory(&data).unwrap().pixels {
+            RGBA8(decoded) => assert!(decoded == pixels),
+            _ => panic!("Expected RGBA8"),
+        }
+        data
+    }
+
+    fn ihdr_color_type(png: &[u8]) -> u8 {
+        png[8 + 8 + 9]
+    }
+
+    #[test]
+    fn test_reduce() {
+        // Opaque black and white becomes 1-bit grayscale.
+        let data = reduce_round_trip(2, 1, vec![0, 0, 0, 255, 255, 255, 255, 255]);
+        assert_eq!((ihdr_color_type(&data), ihdr_bit_depth(&data)), (ffi::COLOR_TYPE_GRAY as u8, 1));
+
+        // Three colors fit a 2-bit palette.
+        let data = reduce_round_trip(3, 1, vec![255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 128]);
+        assert_eq!((ihdr_color_type(&data), ihdr_bit_depth(&data)), (ffi::COLOR_TYPE_PALETTE as u8, 2));
+
+        // 200 gray levels and a single transparent color need 8 bits
+        // either way, so gray with a tRNS color is preferred over a palette.
+        let mut pixels = vec![];
+        for i in 0..400 {
+            let level = (i % 200) as u8;
+            pixels.extend(if i % 7 == 0 { [250, 250, 250, 0] } else { [level, level, level, 255] }.iter());
+        }
+        let data = reduce_round_trip(20, 20, pixels);
+        assert_eq!((ihdr_color_type(&data), ihdr_bit_depth(&data)), (ffi::COLOR_TYPE_GRAY as u8, 8));
+
+        // Partial transparency with too many colors for a palette.
+        let pixels: Vec<u8> = (0..300u32).flat_map(|i| vec![i as u8, (i / 2) as u8, 7, 128]).collect();
+        let data = reduce_round_trip(300, 1, pixels);
+        assert_eq!(ihdr_color_type(&data), ffi::COLOR_TYPE_RGBA as u8);
+
+        // The background follows the image into 1-bit grayscale.
+        let mut img = Image {
+            width: 2,
+            height: 1,
+            pixels: RGB16(vec![0, 0, 0, 0xffff, 0xffff, 0xffff]),
+            metadata: Metadata { background: Some(Background::Rgb(0xffff, 0xffff, 0xffff)), ..Default::default() },
+        };
+        let options = EncodeOptions { reduce: true, ..Default::default() };
+        let data = to_vec_with_options(&mut img, &options).unwrap();
+        assert_eq!(ihdr_bit_depth(&data), 1);
+        assert_eq!(load_png_from_memory(&data).unwrap().metadata.background, Some(Background::Rgb(255, 255, 255)));
+    }
+
+    #[test]
+    fn test_decode_into() {
+        let mut data = vec![];
+        File::open("test/servo-screenshot.png").unwrap().read_to_end(&mut data).unwrap();
+        let img = load_png_from_memory(&data).unwrap();
+        let pixels = match img.pixels {
+            RGBA8(ref pixels) => pixels,
+            _ => panic!("expected RGBA8"),
+        };
+        let (width, height) = dimensions(&data).unwrap();
+        assert_eq!((width, height), (img.width, img.height));
+
+        // Decode into the middle of an atlas twice as wide.
+        let row_bytes = width as usize * 4;
+        let stride = row_bytes * 2;
+        let mut atlas = vec![7u8; stride * height as usize];
+        assert_eq!(decode_into(&data, &mut atlas[row_bytes / 2..], stride, PixelFormat::RGBA8).unwrap(),
+                   (width, height));
+        for (y, row) in atlas.chunks(stride).enumerate() {
+            assert!(row[..row_bytes / 2].iter().all(|&b| b == 7));
+            assert_eq!(&row[row_bytes / 2..row_bytes / 2 * 3], &pixels[y * row_bytes..(y + 1) * row_bytes]);
+            assert!(row[row_bytes / 2 * 3..].iter().all(|&b| b == 7));
+        }
+
+        // Dropping alpha and converting to 16 bits.
+        let mut rgb = vec![0; width as usize * height as usize * 6];
+        decode_into(&data, &mut rgb, width as usize * 6, PixelFormat::RGB16).unwrap();
+        let samples: Vec<u16> = rgb.chunks(2).map(|pair| u16::from_ne_bytes([pair[0], pair[1]])).collect();
+        for (rgba, rgb) in pixels.chunks(4).zip(samples.chunks(3)) {
+            assert_eq!([rgba[0] as u16 * 257, rgba[1] as u16 * 257, rgba[2] as u16 * 257], rgb);
+        }
+
+        let mut small = vec![0; row_bytes * height as usize - 1];
+        assert!(decode_into(&data, &mut small, row_bytes, PixelFormat::RGBA8).is_err());
+        assert!(decode_into(&data, &mut atlas, row_bytes - 1, PixelFormat::RGBA8).is_err());
+    }
+
+    #[test]
+    fn test_decode_into_gray() {
+        let mut data = vec![];
+        File::open("test/gray.png").unwrap().read_to_end(&mut data).unwrap();
+        let img = load_png_from_memory(&data).unwrap();
+        let (width, height) = (img.width as usize, img.height as usize);
+        let mut gray = vec![0; width * height];
+        decode_into(&data, &mut gray, width, PixelFormat::K8).unwrap();
+        match img.pixels {
+            RGBA8(pixels) => {
+                assert!(pixels.chunks(4).zip(gray.iter()).all(|(rgba, &k)| rgba[0] == k));
+            }
+            _ => panic!("expected RGBA8"),
+        }
+    }
+
+    fn swizzle(pixels: &[u8], channels: usize, order: &[usize]) -> Vec<u8> {
+        pixels.chunks(channels).flat_map(|pixel| order.iter().map(move |&i| pixel[i])).collect()
+    }
+
+    #[test]
+    fn test_channel_orders() {
+        let decode = |format| {
+            let options = DecodeOptions { format: Some(format), ..Default::default() };
+            load_png_with_options("test/mozilla-dinosaur-head-logo.png", &options).unwrap()
+        };
+        let rgba = match load_png("test/mozilla-dinosaur-head-logo.png").unwrap().pixels {
+            RGBA8(pixels) => pixels,
+            _ => panic!("expected RGBA8"),
+        };
+        let bgra = swizzle(&rgba, 4, &[2, 1, 0, 3]);
+        let argb = swizzle(&rgba, 4, &[3, 0, 1, 2]);
+        assert_eq!(decode(PixelFormat::BGRA8).pixels, BGRA8(bgra.clone()));
+        assert_eq!(decode(PixelFormat::ARGB8).pixels, ARGB8(argb.clone()));
+
+        // Opaque alpha is added in front for ARGB.
+        let options = DecodeOptions { format: Some(PixelFormat::RGB8), ..Default::default() };
+        let rgb = match load_png_with_options("test/servo-screenshot.png", &options).unwrap().pixels {
+            RGB8(pixels) => pixels,
+            _ => panic!("expected RGB8"),
+        };
+        let options = DecodeOptions { format: Some(PixelFormat::ARGB8), ..Default::default() };
+        let argb_opaque = load_png_with_options("test/servo-screenshot.png", &options).unwrap();
+        assert_eq!(argb_opaque.pixels, ARGB8(rgb.chunks(3).flat_map(|p| vec![255, p[0], p[1], p[2]]).collect()));
+
+        // Encoding any order gives the same file contents.
+        let img = decode(PixelFormat::BGRA8);
+        let encode = |pixels| {
+            let mut img = Image { pixels, ..img.clone() };
+            load_png_from_memory(&to_vec(&mut img).unwrap()).unwrap().pixels
+        };
+        assert_eq!(encode(BGRA8(bgra)), RGBA8(rgba.clone()));
+        assert_eq!(encode(ARGB8(argb)), RGBA8(rgba.clone()));
+        let bgr = swizzle(&rgba, 4, &[2, 1, 0]);
+        let rgb_opaque = swizzle(&rgba, 4, &[0, 1, 2]);
+        let options = DecodeOptions { format: Some(PixelFormat::RGB8), ..Default::default() };
+        let mut img = Image { pixels: BGR8(bgr), ..img.clone() };
+        let decoded = load_png_from_memory_with_options(&to_vec(&mut img).unwrap(), &options).unwrap();
+        assert_eq!(decoded.pixels, RGB8(rgb_opaque));
+    }
+
+    #[test]
+    fn test_to_gray() {
+        // Without a gAMA chunk the weights apply to the stored values.
+        let rgba: Vec<u8> = (0..64 * 64u32).flat_map(|i| vec![(i * 7) as u8, (i / 3) as u8, (i * 13) as u8, (i / 16) as u8])
+                                           .collect();
+        let mut img = Image { width: 64, height: 64, pixels: RGBA8(rgba.clone()), metadata: Default::default() };
+        let options = DecodeOptions { to_gray: Some(GrayWeights::Rec709), ..Default::default() };
+        let data = to_vec(&mut img).unwrap();
+        let img = load_png_from_memory_with_options(&data, &options).unwrap();
+        assert!(img.metadata.had_color);
+        match img.pixels {
+            KA8(pixels) => {
+                for (rgba, ka) in rgba.chunks(4).zip(pixels.chunks(2)) {
+                    let luma = 0.2126 * rgba[0] as f64 + 0.7152 * rgba[1] as f64 + 0.0722 * rgba[2] as f64;
+                    assert!((luma - ka[0] as f64).abs() <= 1.0);
+                    assert_eq!(rgba[3], ka[1]);
+                }
+            }
+            _ => panic!("expected KA8"),
+        }
+
+        // Only red, without alpha.
+        let options = DecodeOptions {
+            to_gray: Some(GrayWeights::Custom { red: 1.0, green: 0.0 }),
+            format: Some(PixelFormat::K8),
+            ..Default::default()
+        };
+        let img = load_png_from_memory_with_options(&data, &options).unwrap();
+        assert_eq!(img.pixels, K8(rgba.chunks(4).map(|pixel| pixel[0]).collect()));
+
+        // Compositing yields K8.
+        let options = DecodeOptions {
+            to_gray: Some(GrayWeights::Rec709),
+            composite: Some(Composite::Color([0, 0, 0])),
+            ..Default::default()
+        };
+        match load_png_with_options("test/servo-screenshot.png", &options).unwrap() {
+            Image { pixels: K8(_), metadata: Metadata { had_color: true, .. }, .. } => {}
+            img => panic!("unexpected {:?}", img.pixels.layout()),
+        }
+
+        let options = DecodeOptions { to_gray: Some(Default::default()), ..Default::default() };
+        assert!(!load_png_with_options("test/gray.png", &options).unwrap().metadata.had_color);
+
+        let options = DecodeOptions {
+            to_gray: Some(GrayWeights::Custom { red: 0.7, green: 0.7 }),
+            ..Default::default()
+        };
+        assert!(load_png_with_options("test/servo-screenshot.png", &options).is_err());
+    }
+
+    #[test]
+    fn test_unfilter() {
+        // The encoder filters rows with libpng's portable code, so the SIMD
+        // unfilters, if built, must give back exactly the encoded pixels.
+        let mut state = 0x2545f491u32;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        };
+        for &filter in &[Filter::Sub, Filter::Average, Filter::Paeth] {
+            for &format in &[PixelFormat::RGB8, PixelFormat::RGBA8] {
+                for width in 1..20 {
+                    // Few distinct samples make ties in the Paeth predictor.
+                    for &mask in &[0xff, 0x03] {
+                        let pixels: Vec<u8> = (0..width * 3 * format.channels()).map(|_| next() & mask).collect();
+                        let mut img = Image {
+                            width: width as u32,
+                            height: 3,
+                            pixels: if format == PixelFormat::RGB8 { RGB8(pixels.clone()) } else { RGBA8(pixels.clone()) },
+                            metadata: Default::default(),
+                        };
+                        let options = EncodeOptions { filter, ..Default::default() };
+                        let png = to_vec_with_options(&mut img, &options).unwrap();
+                        let mut decoded = vec![0; pixels.len()];
+                        decode_into(&png, &mut decoded, width * format.bytes_per_pixel(), format).unwrap();
+                        assert!(decoded == pixels, "{:?} {:?} width {}", filter, format, width);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_checks_size() {
+        let palette = vec![PaletteEntry { red: 0, green: 0, blue: 0, alpha: 255 }];
+        for &(width, height, ref pixels) in &[(9, 2, K1(vec![0; 3])),
+                                              (3, 3, K4(vec![0; 5])),
+                                              (4, 1, I2(vec![], palette.clone())),
+                                              (2, 2, RGB8(vec![0; 13])),
+                                              (0, 5, K8(vec![])),
+                                              (u32::MAX, u32::MAX, RGBA16(vec![0; 8]))] {
+            for &reduce in &[false, true] {
+                let mut img = Image { width, height, pixels: pixels.clone(), metadata: Default::default() };
+                let options = EncodeOptions { reduce, bit_depth: BitDepth::Smallest, ..Default::default() };
+                assert!(to_vec_with_options(&mut img, &options).is_err());
+            }
+        }
+
+        // Packed rows are padded to whole bytes; one sample more or less fails.
+        for &(width, height, ref pixels) in &[(9, 2, K1(vec![0; 4])),
+                                              (3, 3, K4(vec![0; 6])),
+                                              (5, 1, I2(vec![0; 2], palette.clone())),
+                                              (2, 2, RGB8(vec![0; 12])),
+                                              (3, 1, RGB16(vec![0; 9]))] {
+            let mut img = Image { width, height, pixels: pixels.clone(), metadata: Default::default() };
+            assert!(to_vec(&mut img).is_ok());
+            for &extra in &[false, true] {
+                let mut img = Image { width, height, pixels: pixels.clone(), metadata: Default::default() };
+                match img.pixels {
+                    K1(ref mut p) | K4(ref mut p) | I2(ref mut p, _) | RGB8(ref mut p) => {
+                        if extra { p.push(0) } else { p.pop(); }
+                    }
+                    RGB16(ref mut p) => {
+                        if extra { p.push(0) } else { p.pop(); }
+                    }
+                    _ => unreachable!(),
+                }
+                assert_eq!(to_vec(&mut img).unwrap_err(), "pixel data does not match the image size");
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_image_ref() {
+        // Views have no metadata.
+        let img = Image { metadata: Default::default(), ..load_png("test/servo-screenshot.png").unwrap() };
+        let view = img.as_ref().unwrap();
+        assert_eq!(to_vec(view).unwrap(), to_vec(&img).unwrap());
+
+        // The same pixels in a padded framebuffer, and a sub-rectangle.
+        let stride = view.stride + 12;
+        let mut framebuffer = vec![0xaa; stride * img.height as usize];
+        for (dst, src) in framebuffer.chunks_mut(stride).zip(view.data.chunks(view.stride)) {
+            dst[..src.len()].copy_from_slice(src);
+        }
+        let padded = ImageRef { stride, data: &framebuffer, ..view };
+        assert_eq!(load_png_from_memory(&to_vec(padded).unwrap()).unwrap(), img);
+        let sub = padded.sub_image(30, 40, 100, 50).unwrap();
+        let decoded = load_png_from_memory(&to_vec(sub).unwrap()).unwrap();
+        let expected: Vec<u8> = view.data.chunks(view.stride).skip(40).take(50)
+                                    .flat_map(|row| row[30 * 4..130 * 4].to_vec()).collect();
+        assert_eq!(decoded.pixels, RGBA8(expected));
+
+        // Reducing copies the rows first.
+        let options = EncodeOptions { reduce: true, ..Default::default() };
+        let reduced = to_vec_with_options(sub, &options).unwrap();
+        assert_eq!(load_png_from_memory(&reduced).unwrap().pixels, decoded.pixels);
+
+        let img = Image { width: 2, height: 1, pixels: RGB16(vec![1, 2, 3, 0x400, 0x500, 0x600]), metadata: Default::default() };
+        assert_eq!(to_vec(img.as_ref().unwrap()).unwrap(), to_vec(&img).unwrap());
+        let img = Image { width: 2, height: 1, pixels: BGR8(vec![1, 2, 3, 4, 5, 6]), metadata: Default::default() };
+        assert_eq!(to_vec(img.as_ref().unwrap()).unwrap(), to_vec(&img).unwrap());
+        let img = Image { width: 8, height: 1, pixels: K1(vec![0x55]), metadata: Default::default() };
+        assert!(img.as_ref().is_none());
+    }
+
+    #[test]
+    fn test_image_ref_checks() {
+        let data = [0; 24];
+        let view = ImageRef::new(2, 3, PixelFormat::RGBA8, &data);
+        assert!(to_vec(view).is_ok());
+        assert!(to_vec(ImageRef { height: 4, ..view }).is_err());
+        assert!(to_vec(ImageRef { stride: 7, ..view }).is_err());
+        assert!(to_vec(ImageRef { width: 0, ..view }).is_err());
+        // The last row only needs its own pixels.
+        assert!(to_vec(ImageRef { stride: 9, width: 1, ..view }).is_ok());
+        assert!(view.sub_image(1, 1, 1, 2).is_ok());
+        assert!(view.sub_image(1, 1, 2, 1).is_err());
+        assert!(view.sub_image(0, u32::MAX, 1, 2).is_err());
+        assert!(to_vec(ImageRef::new(u32::MAX, u32::MAX, PixelFormat::RGBA16, &data)).is_err());
+    }
+
+    #[test]
+    fn test_accessors() {
+        let img = Image { width: 2, height: 2, pixels: RGB16(vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]), metadata: Default::default() };
+        assert_eq!((img.channels(), img.bytes_per_pixel()), (3, Some(6)));
+        let expected: Vec<u8> = [7u16, 8, 9, 10, 11, 12].iter().flat_map(|sample| sample.to_ne_bytes().to_vec()).collect();
+        assert_eq!(img.row(1), &expected[..]);
+
+        let img = Image { width: 9, height: 2, pixels: K1(vec![1, 0x80, 2, 0]), metadata: Default::default() };
+        assert_eq!((img.channels(), img.bytes_per_pixel()), (1, None));
+        assert_eq!(img.row(1), &[2, 0]);
+        let palette = vec![PaletteEntry { red: 0, green: 0, blue: 0, alpha: 255 }];
+        let img = Image { width: 2, height: 1, pixels: I8(vec![0, 0], palette), metadata: Default::default() };
+        assert_eq!((img.channels(), img.bytes_per_pixel(), img.row(0)), (1, Some(1), &[0, 0][..]));
+    }
 }
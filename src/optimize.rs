@@ -0,0 +1,276 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Lossless size optimization of existing PNG files.
+//!
+//! The image is re-encoded with many combinations of settings in parallel.
+//! Every candidate is decoded again and only kept if its pixels are
+//! identical to those of the input.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use chunks::{chunks, Chunk, ChunkWriter};
+use edit::{strip_metadata, StripPolicy};
+use super::{load_png_from_memory_with_options, to_vec_with_options};
+use super::{DecodeOptions, EncodeOptions, Filter, Image, Metadata, Strategy};
+
+/// How many combinations of settings to try.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Effort {
+    /// Reduce the color type and try two filter choices.
+    Fast,
+    /// Also try every filter with two zlib strategies.
+    #[default]
+    Balanced,
+    /// Also try all strategies, two compression levels and the unreduced
+    /// color type.
+    Exhaustive,
+}
+
+/// Settings for `optimize_with_options`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct OptimizeOptions {
+    pub effort: Effort,
+    /// Drop all ancillary chunks except `tRNS`, which affects the pixels.
+    pub strip: bool,
+    /// Number of threads to encode with, or 0 for one per available core.
+    pub threads: usize,
+}
+
+/// Chunks that describe the pixels in their stored format and are
+/// therefore written anew for each candidate. The encoder does not write
+/// `hIST`, which counts the uses of each entry of the original palette, so
+/// it is dropped.
+const REGENERATED: [[u8; 4]; 4] = [*b"tRNS", *b"bKGD", *b"sBIT", *b"hIST"];
+
+/// Chunks that stay valid when the image data changes, although they are
+/// not marked safe to copy.
+const STILL_VALID: [[u8; 4]; 8] = [*b"gAMA", *b"cHRM", *b"sRGB", *b"iCCP", *b"pHYs", *b"sPLT", *b"tIME", *b"oFFs"];
+
+pub fn optimize(input: &[u8], effort: Effort) -> Result<Vec<u8>, String> {
+    optimize_with_options(input, &OptimizeOptions { effort, ..Default::default() })
+}
+
+/// Returns the smallest encoding found of the image in `input`, which may
+/// be `input` itself. Ancillary chunks are kept unless `options.strip` is
+/// set, apart from unknown chunks that are unsafe to copy and, if the image
+/// is encoded anew, `hIST`.
+pub fn optimize_with_options(input: &[u8], options: &OptimizeOptions) -> Result<Vec<u8>, String> {
+    let decode_options = DecodeOptions { keep_16_bit: true, ..Default::default() };
+    let original = load_png_from_memory_with_options(input, &decode_options)?;
+
+    // Other metadata is copied from the input chunk by chunk.
+    let metadata = if options.strip {
+        Metadata::default()
+    } else {
+        Metadata {
+            background: original.metadata.background,
+            significant_bits: original.metadata.significant_bits,
+            ..Default::default()
+        }
+    };
+    let img = Image { metadata, ..original.clone() };
+
+    let candidates = candidates(options.effort);
+    let threads = match options.threads {
+        0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        n => n,
+    };
+    let next = AtomicUsize::new(0);
+    // The smallest output so far and its candidate, to break ties the same
+    // way on every run.
+    let best: Mutex<Option<(usize, usize, Vec<u8>)>> = Mutex::new(None);
+    thread::scope(|scope| {
+        for _ in 0..threads.min(candidates.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= candidates.len() {
+                    break;
+                }
+                let mut img = img.clone();
+                let data = match to_vec_with_options(&mut img, &candidates[i]) {
+                    Ok(data) => data,
+                    Err(_) => continue,
+                };
+                let identical = match load_png_from_memory_with_options(&data, &decode_options) {
+                    Ok(decoded) => decoded.pixels == original.pixels,
+                    Err(_) => false,
+                };
+                if identical {
+                    let mut best = best.lock().unwrap();
+                    let better = match *best {
+                        Some((len, j, _)) => (data.len(), i) < (len, j),
+                        None => true,
+                    };
+                    if better {
+                        *best = Some((data.len(), i, data));
+                    }
+                }
+            });
+        }
+    });
+
+    let unchanged = if options.strip {
+        strip_metadata(input, &StripPolicy::Keep(vec![*b"tRNS"]))?
+    } else {
+        input.to_vec()
+    };
+    let encoded = match best.into_inner().unwrap() {
+        Some((_, _, data)) => splice(input, &data, !options.strip)?,
+        None => return Ok(unchanged),
+    };
+    Ok(if encoded.len() < unchanged.len() { encoded } else { unchanged })
+}
+
+fn candidates(effort: Effort) -> Vec<EncodeOptions> {
+    let (reductions, filters, strategies, levels): (&[bool], &[Filter], &[Strategy], &[u8]) = match effort {
+        Effort::Fast => (&[true], &[Filter::None, Filter::Adaptive], &[Strategy::Default], &[9]),
+        Effort::Balanced => {
+            (&[true],
+             &[Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth, Filter::Adaptive],
+             &[Strategy::Normal, Strategy::Filtered],
+             &[9])
+        }
+        Effort::Exhaustive => {
+            (&[true, false],
+             &[Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth, Filter::Adaptive],
+             &[Strategy::Normal, Strategy::Filtered, Strategy::HuffmanOnly, Strategy::Rle],
+             &[6, 9])
+        }
+    };
+    let mut candidates = vec![];
+    for &reduce in reductions {
+        for &filter in filters {
+            for &strategy in strategies {
+                for &level in levels {
+                    candidates.push(EncodeOptions {
+                        reduce,
+                        filter,
+                        strategy,
+                        compression_level: Some(level),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+    }
+    candidates
+}
+
+/// Adds the ancillary chunks of `original` to `encoded`, in the same
+/// position relative to the image data.
+fn splice(original: &[u8], encoded: &[u8], keep: bool) -> Result<Vec<u8>, String> {
+    let mut before = vec![];
+    let mut after = vec![];
+    if keep {
+        let mut seen_idat = false;
+        for chunk in chunks(original)? {
+            let chunk = chunk?;
+            if &chunk.chunk_type == b"IDAT" {
+                seen_idat = true;
+            } else if is_copyable(&chunk) {
+                if seen_idat { after.push(chunk) } else { before.push(chunk) }
+            }
+        }
+    }
+
+    let mut writer = ChunkWriter::new(Vec::with_capacity(encoded.len()))?;
+    for chunk in chunks(encoded)? {
+        let chunk = chunk?;
+        if &chunk.chunk_type == b"IEND" {
+            for chunk in &after {
+                writer.copy_chunk(chunk)?;
+            }
+        }
+        writer.copy_chunk(&chunk)?;
+        if &chunk.chunk_type == b"IHDR" {
+            for chunk in &before {
+                writer.copy_chunk(chunk)?;
+            }
+        }
+    }
+    Ok(writer.into_inner())
+}
+
+fn is_copyable(chunk: &Chunk) -> bool {
+    !chunk.is_critical() && !REGENERATED.contains(&chunk.chunk_type) &&
+        (chunk.is_safe_to_copy() || STILL_VALID.contains(&chunk.chunk_type))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Read;
+
+    use chunks::{chunks, ChunkWriter};
+    use edit::set_text;
+    use super::{optimize, optimize_with_options, Effort, OptimizeOptions};
+    use super::super::{load_png_from_memory, to_vec, to_vec_with_options, EncodeOptions, Image, PaletteEntry};
+    use super::super::PixelsByColorType::{I8, RGBA8};
+
+    #[test]
+    fn test_optimize() {
+        let mut pixels = vec![];
+        for i in 0..64 * 64 {
+            let v = (i % 64 / 16 * 80) as u8;
+            pixels.extend(&[v, v, v, 255]);
+        }
+        let mut img = Image { width: 64, height: 64, pixels: RGBA8(pixels), metadata: Default::default() };
+        let input = set_text(&to_vec(&mut img).unwrap(), "Title", "stripes").unwrap();
+
+        let optimized = optimize(&input, Effort::Fast).unwrap();
+        assert!(optimized.len() < input.len());
+        assert_eq!(load_png_from_memory(&optimized).unwrap(), load_png_from_memory(&input).unwrap());
+        assert!(chunks(&optimized).unwrap().any(|chunk| &chunk.unwrap().chunk_type == b"tEXt"));
+
+        let options = OptimizeOptions { strip: true, threads: 2, ..Default::default() };
+        let stripped = optimize_with_options(&input, &options).unwrap();
+        assert!(stripped.len() < optimized.len());
+        assert!(!chunks(&stripped).unwrap().any(|chunk| &chunk.unwrap().chunk_type == b"tEXt"));
+    }
+
+    #[test]
+    fn test_optimize_drops_hist() {
+        let palette: Vec<PaletteEntry> = (0..4).map(|i| {
+            PaletteEntry { red: i * 80, green: 0, blue: 0, alpha: 255 }
+        }).collect();
+        let indices = (0..64 * 64).map(|i| (i % 64 / 16) as u8).collect();
+        let mut img = Image { width: 64, height: 64, pixels: I8(indices, palette), metadata: Default::default() };
+        let options = EncodeOptions { compression_level: Some(0), ..Default::default() };
+        let encoded = to_vec_with_options(&mut img, &options).unwrap();
+
+        // Each entry is used by a quarter of the pixels.
+        let mut writer = ChunkWriter::new(vec![]).unwrap();
+        for chunk in chunks(&encoded).unwrap() {
+            let chunk = chunk.unwrap();
+            writer.copy_chunk(&chunk).unwrap();
+            if &chunk.chunk_type == b"PLTE" {
+                writer.write_chunk(b"hIST", &[0x04, 0x00].repeat(4)).unwrap();
+            }
+        }
+        let input = writer.into_inner();
+        assert!(chunks(&input).unwrap().any(|chunk| &chunk.unwrap().chunk_type == b"hIST"));
+
+        let optimized = optimize(&input, Effort::Fast).unwrap();
+        assert!(optimized.len() < input.len());
+        assert!(!chunks(&optimized).unwrap().any(|chunk| &chunk.unwrap().chunk_type == b"hIST"));
+        assert_eq!(load_png_from_memory(&optimized).unwrap().pixels, load_png_from_memory(&input).unwrap().pixels);
+    }
+
+    #[test]
+    fn test_optimize_keeps_pixels() {
+        let mut input = vec![];
+        File::open("test/mozilla-dinosaur-head-logo.png").unwrap().read_to_end(&mut input).unwrap();
+        let optimized = optimize(&input, Effort::Fast).unwrap();
+        assert!(optimized.len() <= input.len());
+        assert_eq!(load_png_from_memory(&optimized).unwrap().pixels, load_png_from_memory(&input).unwrap().pixels);
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Conversions between packed 1, 2 and 4-bit rows and one sample per byte.
+//! Packed rows store the leftmost sample in the most significant bits and
+//! are padded to a whole byte, as in PNG.
+
+/// Number of bytes in a packed row of `width` samples.
+pub fn packed_row_bytes(width: usize, depth: u8) -> usize {
+    (width * depth as usize).div_ceil(8)
+}
+
+/// Expands packed rows to one sample per byte, without scaling.
+pub fn unpack(data: &[u8], width: usize, height: usize, depth: u8) -> Vec<u8> {
+    let row_bytes = packed_row_bytes(width, depth);
+    let per_byte = 8 / depth as usize;
+    let mask = (1u16 << depth) as u8 - 1;
+    let mut out = Vec::with_capacity(width * height);
+    for row in data.chunks(row_bytes).take(height) {
+        for x in 0..width {
+            let shift = 8 - depth as usize * (x % per_byte + 1);
+            out.push(row[x / per_byte] >> shift & mask);
+        }
+    }
+    out
+}
+
+/// Packs one sample per byte into rows of `depth` bits per sample.
+pub fn pack(samples: &[u8], width: usize, height: usize, depth: u8) -> Vec<u8> {
+    let row_bytes = packed_row_bytes(width, depth);
+    let per_byte = 8 / depth as usize;
+    let mut out = vec![0; row_bytes * height];
+    for (row, samples) in out.chunks_mut(row_bytes).zip(samples.chunks(width)) {
+        for (x, &sample) in samples.iter().enumerate() {
+            let shift = 8 - depth as usize * (x % per_byte + 1);
+            row[x / per_byte] |= sample << shift;
+        }
+    }
+    out
+}
+
+/// The distance between adjacent gray levels of `depth` when scaled to 8 bits.
+pub fn gray_step(depth: u8) -> u8 {
+    (255 / ((1u16 << depth) - 1)) as u8
+}
+
+/// Returns the smallest bit depth at which every 8-bit gray level can be
+/// represented exactly.
+pub fn smallest_gray_depth(samples: &[u8]) -> u8 {
+    let mut depth = 1;
+    for &sample in samples {
+        while depth < 8 && sample % gray_step(depth) != 0 {
+            depth *= 2;
+        }
+        if depth == 8 {
+            break;
+        }
+    }
+    depth
+}
+
+/// Returns the smallest bit depth that can index `entries` palette entries.
+pub fn smallest_index_depth(entries: usize) -> u8 {
+    match entries {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pack, smallest_gray_depth, unpack};
+
+    #[test]
+    fn test_pack_round_trip() {
+        let samples = vec![1, 0, 1, 1, 0,
+                           0, 1, 0, 0, 1];
+        let packed = pack(&samples, 5, 2, 1);
+        assert_eq!(packed, vec![0b10110000, 0b01001000]);
+        assert_eq!(unpack(&packed, 5, 2, 1), samples);
+
+        let samples = vec![3, 0, 2];
+        assert_eq!(unpack(&pack(&samples, 3, 1, 2), 3, 1, 2), samples);
+    }
+
+    #[test]
+    fn test_smallest_gray_depth() {
+        assert_eq!(smallest_gray_depth(&[0, 255, 0]), 1);
+        assert_eq!(smallest_gray_depth(&[0, 85, 255]), 2);
+        assert_eq!(smallest_gray_depth(&[17, 255]), 4);
+        assert_eq!(smallest_gray_depth(&[0, 1]), 8);
+    }
+}
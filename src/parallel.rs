@@ -0,0 +1,411 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Multi-threaded compression of the image data.
+//!
+//! libpng writes the image with neither filtering nor compression, so that
+//! it still takes care of the transformations and all other chunks. The
+//! rows are then filtered and deflated again in slices on several threads.
+//! Every slice but the last ends with a sync flush, which leaves it byte
+//! aligned, so the slices concatenate into a single zlib stream whose
+//! Adler-32 is combined from those of the slices.
+
+use libc::{c_int, c_uint, c_ulong};
+use std::cmp;
+use std::mem;
+use std::thread;
+
+use chunks::{chunks, ChunkWriter};
+use super::{ffi, EncodeOptions, Filter};
+
+/// The size of the deflate window, which is also the most of the previous
+/// slice that is useful as a dictionary.
+const WINDOW_SIZE: usize = 32768;
+
+const IDAT_SIZE: usize = 1 << 20;
+
+/// The largest amount of data passed to zlib at once.
+const MAX_ZLIB_LEN: usize = 1 << 30;
+
+const FILTER_NONE: u8 = 0;
+const FILTER_SUB: u8 = 1;
+const FILTER_UP: u8 = 2;
+const FILTER_AVERAGE: u8 = 3;
+const FILTER_PAETH: u8 = 4;
+
+/// Replaces the image data of `png`, which must be unfiltered, with data
+/// filtered and compressed on `options.threads` threads.
+pub fn recompress(png: &[u8], options: &EncodeOptions) -> Result<Vec<u8>, String> {
+    let mut header = None;
+    let mut stored = vec![];
+    for chunk in chunks(png)? {
+        let chunk = chunk?;
+        match &chunk.chunk_type {
+            b"IHDR" => header = Some(chunk.data),
+            b"IDAT" => stored.extend_from_slice(chunk.data),
+            _ => {}
+        }
+    }
+    let header = match header {
+        Some(header) if header.len() == 13 => header,
+        _ => return Err("missing IHDR chunk".to_string()),
+    };
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let (bit_depth, color_type) = (header[8] as usize, header[9] as c_int);
+    if header[12] != 0 {
+        return Err("interlaced images cannot be compressed in parallel".to_string());
+    }
+    let channels = match color_type {
+        ffi::COLOR_TYPE_GRAY | ffi::COLOR_TYPE_PALETTE => 1,
+        ffi::COLOR_TYPE_GA => 2,
+        ffi::COLOR_TYPE_RGB => 3,
+        _ => 4,
+    };
+    let bits_per_pixel = channels * bit_depth;
+    let row_bytes = (width * bits_per_pixel).div_ceil(8);
+    // Filters compare bytes of neighboring pixels, or neighboring bytes
+    // below 8 bits per pixel.
+    let bpp = cmp::max(1, bits_per_pixel / 8);
+    let raw = inflate(&stored, (row_bytes + 1) * height)?;
+    mem::drop(stored);
+
+    let filter = match options.filter {
+        // Same as libpng, which does not filter palette and low bit depth
+        // images by default.
+        Filter::Default if color_type == ffi::COLOR_TYPE_PALETTE || bit_depth < 8 => Filter::None,
+        Filter::Default => Filter::Adaptive,
+        filter => filter,
+    };
+    let level = options.compression_level.map_or(ffi::Z_DEFAULT_COMPRESSION, |level| level as c_int);
+    let strategy = options.strategy.zlib_strategy().unwrap_or(if filter == Filter::None {
+        ffi::Z_DEFAULT_STRATEGY
+    } else {
+        ffi::Z_FILTERED
+    });
+
+    let slices = cmp::max(1, cmp::min(options.threads, height));
+    let rows_per_slice = cmp::max(1, height.div_ceil(slices));
+    let slice_len = rows_per_slice * (row_bytes + 1);
+
+    let mut filtered = vec![0; raw.len()];
+    thread::scope(|scope| {
+        for (i, out) in filtered.chunks_mut(slice_len).enumerate() {
+            let raw = &raw;
+            scope.spawn(move || {
+                // The row above the first one counts as zeros.
+                let zeros = vec![0; row_bytes];
+                let mut scratch = vec![0; row_bytes];
+                let first = i * rows_per_slice;
+                for (y, out) in (first..).zip(out.chunks_mut(row_bytes + 1)) {
+                    let row = &raw[y * (row_bytes + 1) + 1..(y + 1) * (row_bytes + 1)];
+                    let prev = if y == 0 { &zeros } else { &raw[(y - 1) * (row_bytes + 1) + 1..y * (row_bytes + 1)] };
+                    filter_row(filter, bpp, prev, row, out, &mut scratch);
+                }
+            });
+        }
+    });
+    mem::drop(raw);
+
+    let results: Vec<Result<(Vec<u8>, u32), String>> = thread::scope(|scope| {
+        let handles: Vec<_> = filtered.chunks(slice_len).enumerate().map(|(i, data)| {
+            let start = i * slice_len;
+            let dictionary = &filtered[start.saturating_sub(WINDOW_SIZE)..start];
+            let last = start + data.len() == filtered.len();
+            scope.spawn(move || {
+                let compressed = deflate(data, dictionary, level, strategy, last)?;
+                Ok((compressed, adler32(data)))
+            })
+        }).collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+
+    // A zlib header for a 32K window and the compression level.
+    let cmf = 0x78u8;
+    let level_bits: u8 = match level {
+        0 | 1 => 0,
+        2..=5 => 1,
+        7..=9 => 3,
+        _ => 2,
+    };
+    let flg = level_bits << 6;
+    let flg = flg + (31 - ((cmf as u16 * 256 + flg as u16) % 31) as u8) % 31;
+    let mut zlib = vec![cmf, flg];
+    let mut adler = 1;
+    for (i, result) in results.into_iter().enumerate() {
+        let (compressed, slice_adler) = result?;
+        let len = cmp::min(slice_len, filtered.len() - i * slice_len);
+        adler = if i == 0 { slice_adler } else { adler32_combine(adler, slice_adler, len as u64) };
+        zlib.extend_from_slice(&compressed);
+    }
+    zlib.extend_from_slice(&adler.to_be_bytes());
+
+    let mut writer = ChunkWriter::new(Vec::with_capacity(png.len() / 2 + zlib.len()))?;
+    let mut written = false;
+    for chunk in chunks(png)? {
+        let chunk = chunk?;
+        if &chunk.chunk_type != b"IDAT" {
+            writer.copy_chunk(&chunk)?;
+        } else if !written {
+            written = true;
+            for data in zlib.chunks(IDAT_SIZE) {
+                writer.write_chunk(b"IDAT", data)?;
+            }
+        }
+    }
+    Ok(writer.into_inner())
+}
+
+/// Writes the filter type and filtered bytes of `row` to `out`.
+fn filter_row(filter: Filter, bpp: usize, prev: &[u8], row: &[u8], out: &mut [u8], scratch: &mut [u8]) {
+    let filter_type = match filter {
+        Filter::Sub => FILTER_SUB,
+        Filter::Up => FILTER_UP,
+        Filter::Average => FILTER_AVERAGE,
+        Filter::Paeth => FILTER_PAETH,
+        Filter::Adaptive => {
+            // libpng's heuristic: the smallest sum of the bytes taken as
+            // signed values.
+            let mut best = (u64::MAX, FILTER_NONE);
+            for filter_type in FILTER_NONE..=FILTER_PAETH {
+                apply_filter(filter_type, bpp, prev, row, scratch);
+                let sum = scratch.iter().map(|&b| (b as i8).unsigned_abs() as u64).sum();
+                if sum < best.0 {
+                    best = (sum, filter_type);
+                }
+            }
+            best.1
+        }
+        _ => FILTER_NONE,
+    };
+    out[0] = filter_type;
+    apply_filter(filter_type, bpp, prev, row, &mut out[1..]);
+}
+
+fn apply_filter(filter_type: u8, bpp: usize, prev: &[u8], row: &[u8], out: &mut [u8]) {
+    let bpp = cmp::min(bpp, row.len());
+    match filter_type {
+        FILTER_SUB => {
+            out[..bpp].copy_from_slice(&row[..bpp]);
+            for i in bpp..row.len() {
+                out[i] = row[i].wrapping_sub(row[i - bpp]);
+            }
+        }
+        FILTER_UP => {
+            for i in 0..row.len() {
+                out[i] = row[i].wrapping_sub(prev[i]);
+            }
+        }
+        FILTER_AVERAGE => {
+            for i in 0..bpp {
+                out[i] = row[i].wrapping_sub(prev[i] / 2);
+            }
+            for i in bpp..row.len() {
+                out[i] = row[i].wrapping_sub(((row[i - bpp] as u16 + prev[i] as u16) / 2) as u8);
+            }
+        }
+        FILTER_PAETH => {
+            for i in 0..bpp {
+                out[i] = row[i].wrapping_sub(prev[i]);
+            }
+            for i in bpp..row.len() {
+                out[i] = row[i].wrapping_sub(paeth(row[i - bpp], prev[i], prev[i - bpp]));
+            }
+        }
+        _ => out.copy_from_slice(row),
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut adler = 1;
+    for chunk in data.chunks(MAX_ZLIB_LEN) {
+        adler = unsafe { ffi::adler32(adler, chunk.as_ptr(), chunk.len() as c_uint) };
+    }
+    adler as u32
+}
+
+/// The Adler-32 of two concatenated pieces of data, given the checksums
+/// of both and the length of the second, as in zlib's `adler32_combine`.
+fn adler32_combine(adler1: u32, adler2: u32, len2: u64) -> u32 {
+    const BASE: u64 = 65521;
+    let rem = len2 % BASE;
+    let mut sum1 = adler1 as u64 & 0xffff;
+    let mut sum2 = rem * sum1 % BASE;
+    sum1 += (adler2 as u64 & 0xffff) + BASE - 1;
+    sum2 += (adler1 as u64 >> 16) + (adler2 as u64 >> 16) + BASE - rem;
+    if sum1 >= BASE {
+        sum1 -= BASE;
+    }
+    if sum1 >= BASE {
+        sum1 -= BASE;
+    }
+    if sum2 >= BASE << 1 {
+        sum2 -= BASE << 1;
+    }
+    if sum2 >= BASE {
+        sum2 -= BASE;
+    }
+    (sum1 | sum2 << 16) as u32
+}
+
+/// Compresses `data` to raw deflate data, ending either the whole stream or
+/// with a sync flush.
+fn deflate(data: &[u8], dictionary: &[u8], level: c_int, strategy: c_int, last: bool) -> Result<Vec<u8>, String> {
+    unsafe {
+        let mut stream: ffi::z_stream = mem::zeroed();
+        // Negative window bits omit the zlib header and trailer.
+        if ffi::deflateInit2_(&mut stream, level, ffi::Z_DEFLATED, -15, 8, strategy, ffi::zlibVersion(),
+                              mem::size_of::<ffi::z_stream>() as c_int) != ffi::Z_OK {
+            return Err("could not initialize zlib".to_string());
+        }
+        if !dictionary.is_empty() {
+            ffi::deflateSetDictionary(&mut stream, dictionary.as_ptr(), dictionary.len() as c_uint);
+        }
+        let bound = ffi::deflateBound(&mut stream, data.len() as c_ulong) as usize;
+        let mut out: Vec<u8> = Vec::with_capacity(bound + 64);
+
+        let mut pieces = data.chunks(MAX_ZLIB_LEN).peekable();
+        let mut input = pieces.next().unwrap_or(&[]);
+        loop {
+            let is_last_piece = pieces.peek().is_none();
+            let flush = match (is_last_piece, last) {
+                (false, _) => ffi::Z_NO_FLUSH,
+                (true, false) => ffi::Z_SYNC_FLUSH,
+                (true, true) => ffi::Z_FINISH,
+            };
+            stream.next_in = input.as_ptr();
+            stream.avail_in = input.len() as c_uint;
+            loop {
+                out.reserve(65536);
+                let available = cmp::min(out.capacity() - out.len(), MAX_ZLIB_LEN);
+                stream.next_out = out.as_mut_ptr().add(out.len());
+                stream.avail_out = available as c_uint;
+                let result = ffi::deflate(&mut stream, flush);
+                out.set_len(out.len() + available - stream.avail_out as usize);
+                match result {
+                    ffi::Z_STREAM_END => break,
+                    ffi::Z_OK | ffi::Z_BUF_ERROR => {}
+                    _ => {
+                        ffi::deflateEnd(&mut stream);
+                        return Err("error compressing image data".to_string());
+                    }
+                }
+                // Everything has been consumed and flushed once zlib stops
+                // filling the output buffer.
+                if flush != ffi::Z_FINISH && stream.avail_in == 0 && stream.avail_out != 0 {
+                    break;
+                }
+            }
+            match pieces.next() {
+                Some(piece) => input = piece,
+                None => break,
+            }
+        }
+        ffi::deflateEnd(&mut stream);
+        Ok(out)
+    }
+}
+
+/// Decompresses a zlib stream that should be `expected` bytes long.
+pub fn inflate(data: &[u8], expected: usize) -> Result<Vec<u8>, String> {
+    unsafe {
+        let mut stream: ffi::z_stream = mem::zeroed();
+        if ffi::inflateInit_(&mut stream, ffi::zlibVersion(), mem::size_of::<ffi::z_stream>() as c_int) != ffi::Z_OK {
+            return Err("could not initialize zlib".to_string());
+        }
+        let mut out: Vec<u8> = vec![0; expected];
+        let (mut read, mut written) = (0, 0);
+        let result = loop {
+            let input = cmp::min(data.len() - read, MAX_ZLIB_LEN);
+            let output = cmp::min(expected - written, MAX_ZLIB_LEN);
+            stream.next_in = data[read..].as_ptr();
+            stream.avail_in = input as c_uint;
+            stream.next_out = out[written..].as_mut_ptr();
+            stream.avail_out = output as c_uint;
+            let result = ffi::inflate(&mut stream, ffi::Z_NO_FLUSH);
+            read += input - stream.avail_in as usize;
+            written += output - stream.avail_out as usize;
+            // Stop at the end of the stream, on errors and when no progress
+            // is possible.
+            if result != ffi::Z_OK || (input == 0 && output == 0) {
+                break result;
+            }
+        };
+        ffi::inflateEnd(&mut stream);
+        if result != ffi::Z_STREAM_END || written != expected {
+            return Err("corrupt image data".to_string());
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chunks::chunks;
+    use super::{adler32, adler32_combine, inflate};
+    use super::super::{load_png_from_memory, to_vec, to_vec_with_options, EncodeOptions, Filter, Image};
+    use super::super::PixelsByColorType::{K1, RGB16, RGBA8};
+
+    #[test]
+    fn test_adler32_combine() {
+        let data: Vec<u8> = (0..100000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let (a, b) = data.split_at(40000);
+        assert_eq!(adler32_combine(adler32(a), adler32(b), b.len() as u64), adler32(&data));
+    }
+
+    /// Encodes `img` with `options` and checks that the image data is a
+    /// single valid zlib stream that decodes to the same pixels.
+    fn check_parallel(img: &Image, options: &EncodeOptions) {
+        let expected = load_png_from_memory(&to_vec(img).unwrap()).unwrap();
+        let data = to_vec_with_options(img, options).unwrap();
+        assert_eq!(load_png_from_memory(&data).unwrap().pixels, expected.pixels);
+
+        let chunks: Vec<_> = chunks(&data).unwrap().map(|chunk| chunk.unwrap()).collect();
+        let ihdr = chunks[0].data;
+        let channels = match ihdr[9] { 0 | 3 => 1, 4 => 2, 2 => 3, _ => 4 };
+        let row_bytes = (img.width as usize * channels * ihdr[8] as usize).div_ceil(8);
+        let idat: Vec<u8> = chunks.iter().filter(|chunk| &chunk.chunk_type == b"IDAT")
+                                  .flat_map(|chunk| chunk.data.to_vec()).collect();
+        let raw = inflate(&idat, (row_bytes + 1) * img.height as usize).unwrap();
+        assert!(raw.chunks(row_bytes + 1).all(|row| row[0] <= 4));
+    }
+
+    #[test]
+    fn test_parallel_encode() {
+        let mut pixels = vec![];
+        for y in 0..300u32 {
+            for x in 0..301u32 {
+                pixels.extend(&[(x ^ y) as u8, (x * y) as u8, (x + y) as u8, (y * 3) as u8]);
+            }
+        }
+        let img = Image { width: 301, height: 300, pixels: RGBA8(pixels), metadata: Default::default() };
+        for &filter in [Filter::Default, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth].iter() {
+            check_parallel(&img, &EncodeOptions { threads: 4, filter, ..Default::default() });
+        }
+        check_parallel(&img, &EncodeOptions { threads: 7, compression_level: Some(1), ..Default::default() });
+
+        let img = Image { width: 1, height: 3, pixels: RGB16(vec![1, 2, 3, 400, 500, 600, 7, 8, 9]), metadata: Default::default() };
+        check_parallel(&img, &EncodeOptions { threads: 8, ..Default::default() });
+
+        let img = Image { width: 9, height: 5, pixels: K1([0b10101010, 0b10000000].repeat(5)), metadata: Default::default() };
+        check_parallel(&img, &EncodeOptions { threads: 2, filter: Filter::Adaptive, ..Default::default() });
+    }
+}
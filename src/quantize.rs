@@ -0,0 +1,350 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Lossy reduction of `RGBA8` images to a palette of at most 256 colors.
+//!
+//! The palette is built by median cut and refined with k-means. The result
+//! only depends on the pixels and the options, so repeated runs produce
+//! identical images.
+
+use std::collections::HashMap;
+
+use super::{Background, Image, Metadata, PaletteEntry, PixelsByColorType};
+
+/// Settings for `quantize`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct QuantizeOptions {
+    /// The largest palette to produce, 2 - 256. Fewer colors give smaller
+    /// files at lower quality.
+    pub max_colors: usize,
+    /// Diffuse the quantization error with Floyd–Steinberg dithering.
+    pub dither: bool,
+    /// Number of k-means passes refining the median cut palette.
+    pub refinement_passes: usize,
+}
+
+impl Default for QuantizeOptions {
+    fn default() -> QuantizeOptions {
+        QuantizeOptions {
+            max_colors: 256,
+            dither: false,
+            refinement_passes: 3,
+        }
+    }
+}
+
+/// A distinct color and the number of pixels that have it.
+#[derive(Clone, Copy)]
+struct Bucket {
+    color: [u8; 4],
+    count: u32,
+}
+
+/// Quantizes an `RGBA8` image to `I8` pixels with a palette of at most
+/// `options.max_colors` entries. Images that already have few enough
+/// colors are converted exactly. Metadata is copied, with a background
+/// color replaced by its closest palette entry, or dropped if it does not
+/// fit the 8-bit pixels.
+pub fn quantize(img: &Image, options: &QuantizeOptions) -> Result<Image, String> {
+    let pixels = match img.pixels {
+        PixelsByColorType::RGBA8(ref pixels) => pixels,
+        _ => return Err("only RGBA8 images can be quantized".to_string()),
+    };
+    if options.max_colors < 2 || options.max_colors > 256 {
+        return Err("the palette must have 2 to 256 colors".to_string());
+    }
+    if pixels.len() != img.width as usize * img.height as usize * 4 {
+        return Err("pixel data does not match the image size".to_string());
+    }
+
+    let colors: Vec<[u8; 4]> = pixels.chunks(4).map(|p| normalize([p[0], p[1], p[2], p[3]])).collect();
+    let histogram = histogram(&colors);
+    let exact = histogram.len() <= options.max_colors;
+    let mut palette: Vec<[u8; 4]> = if exact {
+        histogram.iter().map(|bucket| bucket.color).collect()
+    } else {
+        let mut palette = median_cut(&histogram, options.max_colors);
+        for _ in 0..options.refinement_passes {
+            palette = refine(&histogram, &palette);
+        }
+        palette
+    };
+
+    // Transparent entries go first so that tRNS stays short.
+    palette.sort_by_key(|color| (color[3] == 0xff, *color));
+    palette.dedup();
+
+    let indices = if options.dither && !exact {
+        dither(&colors, img.width as usize, &palette)
+    } else {
+        let mut cache = HashMap::new();
+        colors.iter().map(|&color| *cache.entry(color).or_insert_with(|| nearest(&palette, color))).collect()
+    };
+
+    // The background is at the 8 bits of the pixels, as decoding leaves it.
+    let background = img.metadata.background.and_then(|background| match background {
+        Background::Rgb(r, g, b) if (r | g | b) <= 0xff => {
+            Some(Background::Index(nearest(&palette, [r as u8, g as u8, b as u8, 0xff])))
+        }
+        Background::Gray(v) if v <= 0xff => {
+            Some(Background::Index(nearest(&palette, [v as u8, v as u8, v as u8, 0xff])))
+        }
+        _ => None,
+    });
+    let palette = palette.iter().map(|c| PaletteEntry { red: c[0], green: c[1], blue: c[2], alpha: c[3] }).collect();
+    Ok(Image {
+        width: img.width,
+        height: img.height,
+        pixels: PixelsByColorType::I8(indices, palette),
+        metadata: Metadata { background, ..img.metadata.clone() },
+    })
+}
+
+/// All fully transparent pixels look the same, so give them one color.
+fn normalize(color: [u8; 4]) -> [u8; 4] {
+    if color[3] == 0 { [0; 4] } else { color }
+}
+
+/// Returns the distinct colors in ascending order with their counts.
+fn histogram(colors: &[[u8; 4]]) -> Vec<Bucket> {
+    let mut sorted = colors.to_vec();
+    sorted.sort_unstable();
+    let mut histogram: Vec<Bucket> = vec![];
+    for color in sorted {
+        match histogram.last_mut() {
+            Some(bucket) if bucket.color == color => bucket.count += 1,
+            _ => histogram.push(Bucket { color, count: 1 }),
+        }
+    }
+    histogram
+}
+
+/// Splits the color space into `max_colors` boxes, each time halving the
+/// box with the widest channel range at its weighted median.
+fn median_cut(histogram: &[Bucket], max_colors: usize) -> Vec<[u8; 4]> {
+    let mut boxes = vec![histogram.to_vec()];
+    while boxes.len() < max_colors {
+        let widest = boxes.iter().enumerate()
+                          .filter(|&(_, b)| b.len() > 1)
+                          .map(|(i, b)| (i, widest_channel(b)))
+                          .max_by_key(|&(i, (_, range))| (range, usize::MAX - i));
+        let (i, channel) = match widest {
+            Some((i, (channel, _))) => (i, channel),
+            None => break,
+        };
+        let mut b = boxes.swap_remove(i);
+        b.sort_by_key(|bucket| (bucket.color[channel], bucket.color));
+        let total: u64 = b.iter().map(|bucket| bucket.count as u64).sum();
+        let mut seen = 0;
+        let mut split = 1;
+        for (j, bucket) in b.iter().enumerate() {
+            seen += bucket.count as u64;
+            if seen * 2 >= total {
+                split = j + 1;
+                break;
+            }
+        }
+        let split = split.min(b.len() - 1);
+        let upper = b.split_off(split);
+        boxes.push(b);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|b| mean(b.iter().map(|bucket| (bucket.color, bucket.count)))).collect()
+}
+
+/// Returns the channel with the largest range of values and that range.
+fn widest_channel(buckets: &[Bucket]) -> (usize, u8) {
+    (0..4).map(|channel| {
+        let min = buckets.iter().map(|bucket| bucket.color[channel]).min().unwrap();
+        let max = buckets.iter().map(|bucket| bucket.color[channel]).max().unwrap();
+        (channel, max - min)
+    }).max_by_key(|&(channel, range)| (range, 3 - channel)).unwrap()
+}
+
+/// The weighted average of some colors.
+fn mean<I: Iterator<Item = ([u8; 4], u32)>>(colors: I) -> [u8; 4] {
+    let mut sum = [0u64; 4];
+    let mut total = 0u64;
+    for (color, count) in colors {
+        for c in 0..4 {
+            sum[c] += color[c] as u64 * count as u64;
+        }
+        total += count as u64;
+    }
+    let mut mean = [0; 4];
+    for c in 0..4 {
+        mean[c] = ((sum[c] + total / 2) / total) as u8;
+    }
+    normalize(mean)
+}
+
+/// One k-means pass: moves every palette entry to the mean of the colors
+/// closest to it. Entries without any colors are kept.
+fn refine(histogram: &[Bucket], palette: &[[u8; 4]]) -> Vec<[u8; 4]> {
+    let mut clusters: Vec<Vec<([u8; 4], u32)>> = vec![vec![]; palette.len()];
+    for bucket in histogram {
+        clusters[nearest(palette, bucket.color) as usize].push((bucket.color, bucket.count));
+    }
+    clusters.into_iter().zip(palette.iter()).map(|(cluster, &color)| {
+        if cluster.is_empty() { color } else { mean(cluster.into_iter()) }
+    }).collect()
+}
+
+/// The squared distance between two colors, comparing them as they would
+/// look composited onto black and onto white.
+fn distance(a: [i32; 4], b: [u8; 4]) -> i64 {
+    let mut distance = 0;
+    let alpha = a[3] - b[3] as i32;
+    for c in 0..3 {
+        let black = a[c] * a[3] - b[c] as i32 * b[3] as i32;
+        let white = black - alpha * 255;
+        distance += (black as i64).pow(2) + (white as i64).pow(2);
+    }
+    distance
+}
+
+fn nearest(palette: &[[u8; 4]], color: [u8; 4]) -> u8 {
+    let color = [color[0] as i32, color[1] as i32, color[2] as i32, color[3] as i32];
+    nearest_to(palette, color)
+}
+
+fn nearest_to(palette: &[[u8; 4]], color: [i32; 4]) -> u8 {
+    let mut best = (i64::MAX, 0);
+    for (i, &entry) in palette.iter().enumerate() {
+        let distance = distance(color, entry);
+        if distance < best.0 {
+            best = (distance, i);
+        }
+    }
+    best.1 as u8
+}
+
+/// Maps pixels to the palette with Floyd–Steinberg error diffusion.
+fn dither(colors: &[[u8; 4]], width: usize, palette: &[[u8; 4]]) -> Vec<u8> {
+    let mut indices = Vec::with_capacity(colors.len());
+    // Errors for the current and the next row, in 1/16ths.
+    let mut current = vec![[0i32; 4]; width + 2];
+    let mut next = vec![[0i32; 4]; width + 2];
+    for row in colors.chunks(width) {
+        for (x, color) in row.iter().enumerate() {
+            let mut wanted = [0; 4];
+            for c in 0..4 {
+                wanted[c] = (color[c] as i32 + current[x + 1][c] / 16).clamp(0, 255);
+            }
+            let index = nearest_to(palette, wanted);
+            indices.push(index);
+            let chosen = palette[index as usize];
+            for c in 0..4 {
+                let error = wanted[c] - chosen[c] as i32;
+                current[x + 2][c] += error * 7;
+                next[x][c] += error * 3;
+                next[x + 1][c] += error * 5;
+                next[x + 2][c] += error;
+            }
+        }
+        current = next;
+        next = vec![[0; 4]; width + 2];
+    }
+    indices
+}
+
+#[cfg(test)]
+mod test {
+    use super::{quantize, QuantizeOptions};
+    use super::super::{load_png, load_png_from_memory, to_vec, Background, Image, Metadata};
+    use super::super::PixelsByColorType::{I8, RGB16, RGBA8};
+
+    fn gradient() -> Image {
+        let mut pixels = vec![];
+        for y in 0..32u32 {
+            for x in 0..32u32 {
+                pixels.extend(&[(x * 8) as u8, (y * 8) as u8, ((x + y) * 4) as u8, if x < 4 { 0 } else { 255 }]);
+            }
+        }
+        Image { width: 32, height: 32, pixels: RGBA8(pixels), metadata: Default::default() }
+    }
+
+    #[test]
+    fn test_quantize_exact() {
+        let img = Image {
+            width: 3,
+            height: 1,
+            pixels: RGBA8(vec![255, 0, 0, 255, 1, 2, 3, 0, 255, 0, 0, 255]),
+            metadata: Default::default(),
+        };
+        let mut quantized = quantize(&img, &Default::default()).unwrap();
+        match quantized.pixels {
+            I8(ref indices, ref palette) => {
+                assert_eq!(palette.len(), 2);
+                assert_eq!(palette[0].alpha, 0);
+                assert_eq!(indices, &vec![1, 0, 1]);
+            }
+            _ => panic!("Expected I8"),
+        }
+        match load_png_from_memory(&to_vec(&mut quantized).unwrap()).unwrap().pixels {
+            RGBA8(pixels) => assert_eq!(pixels, vec![255, 0, 0, 255, 0, 0, 0, 0, 255, 0, 0, 255]),
+            _ => panic!("Expected RGBA8"),
+        }
+    }
+
+    #[test]
+    fn test_quantize_limits_colors() {
+        for &dither in [false, true].iter() {
+            let options = QuantizeOptions { max_colors: 16, dither, ..Default::default() };
+            let first = quantize(&gradient(), &options).unwrap();
+            let second = quantize(&gradient(), &options).unwrap();
+            match (first.pixels, second.pixels) {
+                (I8(indices, palette), I8(indices2, palette2)) => {
+                    assert!(palette.len() <= 16);
+                    assert!(indices.iter().all(|&index| (index as usize) < palette.len()));
+                    assert!(indices == indices2 && palette == palette2);
+                    // The transparent columns keep a transparent color.
+                    assert_eq!(palette[indices[0] as usize].alpha, 0);
+                    assert_eq!(palette[indices[31] as usize].alpha, 255);
+                }
+                _ => panic!("Expected I8"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantize_16_bit_background() {
+        // The 16-bit bKGD decodes to its high byte, 0x12, which is the first pixel.
+        let mut img = Image {
+            width: 2,
+            height: 1,
+            pixels: RGB16(vec![0x1200, 0x1200, 0x1200, 0x3400, 0x3400, 0x3400]),
+            metadata: Metadata { background: Some(Background::Rgb(0x1234, 0x1234, 0x1234)), ..Default::default() },
+        };
+        let decoded = load_png_from_memory(&to_vec(&mut img).unwrap()).unwrap();
+        assert_eq!(decoded.metadata.background, Some(Background::Rgb(0x12, 0x12, 0x12)));
+        let quantized = quantize(&decoded, &Default::default()).unwrap();
+        match quantized.pixels {
+            I8(ref indices, ref palette) => {
+                assert_eq!(palette[indices[0] as usize].red, 0x12);
+                assert_eq!(quantized.metadata.background, Some(Background::Index(indices[0])));
+            }
+            _ => panic!("Expected I8"),
+        }
+
+        // A background that does not fit the pixels is dropped rather than cut short.
+        let img = Image { metadata: img.metadata.clone(), ..decoded };
+        assert_eq!(quantize(&img, &Default::default()).unwrap().metadata.background, None);
+    }
+
+    #[test]
+    fn test_quantize_photo() {
+        let img = load_png("test/servo-screenshot.png").unwrap();
+        let options = QuantizeOptions { max_colors: 64, dither: true, ..Default::default() };
+        let mut quantized = quantize(&img, &options).unwrap();
+        let data = to_vec(&mut quantized).unwrap();
+        let decoded = load_png_from_memory(&data).unwrap();
+        assert_eq!((decoded.width, decoded.height), (img.width, img.height));
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Lossless reduction of the color type and bit depth before encoding.
+//!
+//! The reduced image decodes to exactly the same pixels as the original.
+
+use std::cmp;
+use std::collections::HashMap;
+
+use super::{pack, Background, Image, Metadata, PaletteEntry, PixelsByColorType, SignificantBits};
+
+/// An image in its smallest exact representation.
+pub struct Reduced {
+    pub pixels: PixelsByColorType,
+    pub metadata: Metadata,
+    /// The single fully transparent color, written to `tRNS` for grayscale
+    /// and truecolor images.
+    pub transparent: Option<Background>,
+}
+
+/// Returns a smaller representation of `img`, or `None` if the pixel format
+/// is already as small as it gets. Bit depths of grayscale and palette
+/// results are reduced by the encoder afterwards.
+pub fn reduce(img: &Image) -> Option<Reduced> {
+    let (depth, channels, samples) = match img.pixels {
+        PixelsByColorType::K8(ref p) => (8, 1, p.iter().map(|&v| v as u16).collect()),
+        PixelsByColorType::KA8(ref p) => (8, 2, p.iter().map(|&v| v as u16).collect()),
+        PixelsByColorType::RGB8(ref p) => (8, 3, p.iter().map(|&v| v as u16).collect()),
+        PixelsByColorType::RGBA8(ref p) => (8, 4, p.iter().map(|&v| v as u16).collect()),
+        PixelsByColorType::K16(ref p) => (16, 1, p.clone()),
+        PixelsByColorType::KA16(ref p) => (16, 2, p.clone()),
+        PixelsByColorType::RGB16(ref p) => (16, 3, p.clone()),
+        PixelsByColorType::RGBA16(ref p) => (16, 4, p.clone()),
+        _ => return None,
+    };
+    let pixel_count = img.width as usize * img.height as usize;
+    if samples.len() != pixel_count * channels || pixel_count == 0 {
+        return None;
+    }
+
+    // Reduce 16-bit samples that are exact multiples of 257 to 8 bits.
+    let (depth, samples) = if depth == 16 && samples.iter().all(|&v| v.is_multiple_of(257)) {
+        (8, samples.iter().map(|&v| v / 257).collect())
+    } else {
+        (depth, samples)
+    };
+    let max = if depth == 8 { 0xff } else { 0xffff };
+
+    let pixel = |i: usize| -> [u16; 4] {
+        let p = &samples[i * channels..(i + 1) * channels];
+        match channels {
+            1 => [p[0], p[0], p[0], max],
+            2 => [p[0], p[0], p[0], p[1]],
+            3 => [p[0], p[1], p[2], max],
+            _ => [p[0], p[1], p[2], p[3]],
+        }
+    };
+
+    let is_gray = (0..pixel_count).all(|i| {
+        let p = pixel(i);
+        p[0] == p[1] && p[1] == p[2]
+    });
+    let is_opaque = (0..pixel_count).all(|i| pixel(i)[3] == max);
+
+    // A single fully transparent color that no opaque pixel shares can be
+    // stored in tRNS instead of an alpha channel.
+    let mut transparent = None;
+    if !is_opaque {
+        let mut key = None;
+        let mut usable = true;
+        for i in 0..pixel_count {
+            let p = pixel(i);
+            if p[3] == 0 {
+                match key {
+                    None => key = Some(p),
+                    Some(k) if k != p => usable = false,
+                    _ => {}
+                }
+            } else if p[3] != max {
+                usable = false;
+            }
+            if !usable {
+                break;
+            }
+        }
+        if usable {
+            let key = key.unwrap();
+            if (0..pixel_count).all(|i| {
+                let p = pixel(i);
+                p[3] == 0 || p[..3] != key[..3]
+            }) {
+                transparent = Some(key);
+            }
+        }
+    }
+
+    // Bits per pixel of the best grayscale or truecolor representation.
+    let has_alpha = !is_opaque && transparent.is_none();
+    let direct_channels = match (is_gray, has_alpha) {
+        (true, false) => 1,
+        (true, true) => 2,
+        (false, false) => 3,
+        (false, true) => 4,
+    };
+    let direct_depth = if direct_channels == 1 && depth == 8 {
+        let levels: Vec<u8> = (0..pixel_count).map(|i| pixel(i)[0] as u8).collect();
+        pack::smallest_gray_depth(&levels) as usize
+    } else {
+        depth
+    };
+    let direct_bits = direct_channels * direct_depth;
+
+    // Palettes only hold 8-bit samples.
+    if depth == 8 {
+        if let Some(reduced) = to_palette(img, (0..pixel_count).map(&pixel), direct_bits) {
+            return Some(reduced);
+        }
+    }
+
+    let original_depth = img.pixels.layout().0 as usize;
+    if direct_channels == channels && depth == original_depth {
+        return None;
+    }
+
+    let mut metadata = img.metadata.clone();
+
+    if let Some(background) = metadata.background {
+        let value = |v: u16| if depth == original_depth { v } else { v / 257 };
+        let divisible = |v: u16| depth == original_depth || v.is_multiple_of(257);
+        metadata.background = match background {
+            Background::Gray(v) if divisible(v) => Some(Background::Gray(value(v))),
+            Background::Rgb(r, g, b) if divisible(r) && divisible(g) && divisible(b) => {
+                if is_gray {
+                    if r != g || g != b {
+                        return None;
+                    }
+                    Some(Background::Gray(value(r)))
+                } else {
+                    Some(Background::Rgb(value(r), value(g), value(b)))
+                }
+            }
+            _ => return None,
+        };
+    }
+    metadata.significant_bits = metadata.significant_bits.map(|sig_bit| {
+        let sig_bit = if is_gray {
+            let gray = cmp::max(sig_bit.gray, cmp::max(sig_bit.red, cmp::max(sig_bit.green, sig_bit.blue)));
+            SignificantBits { gray, ..sig_bit }
+        } else {
+            sig_bit
+        };
+        clamp_sig_bit(sig_bit, depth as u8)
+    });
+
+    let mut out = Vec::with_capacity(pixel_count * direct_channels);
+    for i in 0..pixel_count {
+        let p = pixel(i);
+        match direct_channels {
+            1 => out.push(p[0]),
+            2 => out.extend_from_slice(&[p[0], p[3]]),
+            3 => out.extend_from_slice(&p[..3]),
+            _ => out.extend_from_slice(&p),
+        }
+    }
+    let pixels = match (direct_channels, depth) {
+        (1, 8) => PixelsByColorType::K8(out.iter().map(|&v| v as u8).collect()),
+        (2, 8) => PixelsByColorType::KA8(out.iter().map(|&v| v as u8).collect()),
+        (3, 8) => PixelsByColorType::RGB8(out.iter().map(|&v| v as u8).collect()),
+        (_, 8) => PixelsByColorType::RGBA8(out.iter().map(|&v| v as u8).collect()),
+        (1, _) => PixelsByColorType::K16(out),
+        (2, _) => PixelsByColorType::KA16(out),
+        (3, _) => PixelsByColorType::RGB16(out),
+        (_, _) => PixelsByColorType::RGBA16(out),
+    };
+    let transparent = transparent.map(|key| {
+        if is_gray { Background::Gray(key[0]) } else { Background::Rgb(key[0], key[1], key[2]) }
+    });
+    Some(Reduced { pixels, metadata, transparent })
+}
+
+/// Converts an image with at most 256 distinct colors to a palette, if that
+/// takes fewer than `direct_bits` bits per pixel.
+fn to_palette<I>(img: &Image, pixels: I, direct_bits: usize) -> Option<Reduced>
+    where I: Iterator<Item = [u16; 4]> {
+    let mut colors = HashMap::new();
+    let mut palette = vec![];
+    let mut indices = vec![];
+    for p in pixels {
+        let index = match colors.get(&p) {
+            Some(&index) => index,
+            None if palette.len() == 256 => return None,
+            None => {
+                let index = palette.len() as u8;
+                colors.insert(p, index);
+                palette.push(PaletteEntry { red: p[0] as u8, green: p[1] as u8, blue: p[2] as u8, alpha: p[3] as u8 });
+                index
+            }
+        };
+        indices.push(index);
+    }
+
+    let mut metadata = img.metadata.clone();
+    if let Some(background) = metadata.background {
+        metadata.background = Some(Background::Index(palette_background(&img.pixels, background, &mut palette)?));
+    }
+    if pack::smallest_index_depth(palette.len()) as usize >= direct_bits {
+        return None;
+    }
+    metadata.significant_bits = metadata.significant_bits.map(|sig_bit| {
+        clamp_sig_bit(to_color_sig_bit(&img.pixels, sig_bit), 8)
+    });
+    Some(Reduced {
+        pixels: PixelsByColorType::I8(indices, palette),
+        metadata,
+        transparent: None,
+    })
+}
+
+/// Finds or adds the background color in the palette.
+fn palette_background(pixels: &PixelsByColorType, background: Background,
+                      palette: &mut Vec<PaletteEntry>) -> Option<u8> {
+    let scale = |v: u16| if pixels.layout().0 == 16 {
+        if v.is_multiple_of(257) { Some((v / 257) as u8) } else { None }
+    } else {
+        Some(v as u8)
+    };
+    let (r, g, b) = match background {
+        Background::Gray(v) => (scale(v)?, scale(v)?, scale(v)?),
+        Background::Rgb(r, g, b) => (scale(r)?, scale(g)?, scale(b)?),
+        Background::Index(_) => return None,
+    };
+    if let Some(index) = palette.iter().position(|e| e.red == r && e.green == g && e.blue == b && e.alpha == 0xff) {
+        return Some(index as u8);
+    }
+    if palette.len() == 256 {
+        return None;
+    }
+    palette.push(PaletteEntry { red: r, green: g, blue: b, alpha: 0xff });
+    Some((palette.len() - 1) as u8)
+}
+
+fn to_color_sig_bit(pixels: &PixelsByColorType, sig_bit: SignificantBits) -> SignificantBits {
+    let (_, _, channels) = pixels.layout();
+    if channels < 3 {
+        SignificantBits { red: sig_bit.gray, green: sig_bit.gray, blue: sig_bit.gray, ..sig_bit }
+    } else {
+        sig_bit
+    }
+}
+
+fn clamp_sig_bit(sig_bit: SignificantBits, depth: u8) -> SignificantBits {
+    SignificantBits {
+        red: cmp::min(sig_bit.red, depth),
+        green: cmp::min(sig_bit.green, depth),
+        blue: cmp::min(sig_bit.blue, depth),
+        gray: cmp::min(sig_bit.gray, depth),
+        alpha: cmp::min(sig_bit.alpha, depth),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::reduce;
+    use super::super::{Background, Image, PaletteEntry};
+    use super::super::PixelsByColorType::{I8, K16, KA8, RGB16, RGBA8};
+
+    fn rgba8(pixels: Vec<u8>) -> Image {
+        Image { width: pixels.len() as u32 / 4, height: 1, pixels: RGBA8(pixels), metadata: Default::default() }
+    }
+
+    #[test]
+    fn test_reduce() {
+        let reduced = reduce(&rgba8(vec![1, 2, 3, 255, 4, 5, 6, 0])).unwrap();
+        match reduced.pixels {
+            I8(indices, palette) => {
+                assert_eq!(indices, vec![0, 1]);
+                assert_eq!(palette[1], PaletteEntry { red: 4, green: 5, blue: 6, alpha: 0 });
+            }
+            _ => panic!("Expected I8"),
+        }
+
+        // A transparent color that also appears opaque cannot go to tRNS.
+        let mut pixels: Vec<u8> = (0..=255).flat_map(|v| vec![v, v, v, 255]).collect();
+        pixels.extend(&[9, 9, 9, 0]);
+        let reduced = reduce(&rgba8(pixels)).unwrap();
+        assert!(reduced.transparent.is_none());
+        match reduced.pixels {
+            KA8(samples) => assert_eq!(&samples[510..], &[255, 255, 9, 0]),
+            _ => panic!("Expected KA8"),
+        }
+
+        let img = Image { width: 2, height: 1, pixels: RGB16(vec![3, 3, 3, 0, 0, 0]), metadata: Default::default() };
+        let reduced = reduce(&img).unwrap();
+        match reduced.pixels {
+            K16(samples) => assert_eq!(samples, vec![3, 0]),
+            _ => panic!("Expected K16"),
+        }
+
+        // The background cannot be reduced to 8 bits along with the pixels.
+        let mut img = Image { width: 1, height: 1, pixels: RGB16(vec![257, 514, 771]), metadata: Default::default() };
+        img.metadata.background = Some(Background::Rgb(1, 1, 1));
+        assert!(reduce(&img).is_none());
+    }
+}
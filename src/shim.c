@@ -4,6 +4,32 @@
 
 #include <png.h>
 
+/* Keep in step with MIN_SYSTEM_VERSION in png-sys/build.rs. */
+#if PNG_LIBPNG_VER < 10600
+#error "libpng 1.6.0 or later is required"
+#endif
+
 jmp_buf *pngshim_jmpbuf(png_struct *png_ptr) {
   return &png_jmpbuf(png_ptr);
 }
+
+/* Reads the image data. Returns 0 if libpng reported an error, so that the
+ * caller can free the rows it allocated after setting its own jump buffer,
+ * which this replaces. */
+int pngshim_read_image(png_struct *png_ptr, png_bytepp rows) {
+  if (setjmp(png_jmpbuf(png_ptr))) {
+    return 0;
+  }
+  png_read_image(png_ptr, rows);
+  return 1;
+}
+
+/* Reads the chunks after the image data, up to IEND. Returns 0 if libpng
+ * reported an error, such as a missing or truncated IEND. */
+int pngshim_read_end(png_struct *png_ptr, png_info *info_ptr) {
+  if (setjmp(png_jmpbuf(png_ptr))) {
+    return 0;
+  }
+  png_read_end(png_ptr, info_ptr);
+  return 1;
+}
@@ -0,0 +1,126 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ffi;
+
+/// The last modification time stored in a `tIME` chunk, in UTC.
+#[cfg_attr(feature="serde-serialization", derive(Deserialize, Serialize))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PngTime {
+    pub year: u16,
+    /// 1 - 12
+    pub month: u8,
+    /// 1 - 31
+    pub day: u8,
+    /// 0 - 23
+    pub hour: u8,
+    /// 0 - 59
+    pub minute: u8,
+    /// 0 - 60, allowing for leap seconds
+    pub second: u8,
+}
+
+impl PngTime {
+    /// Converts a system time to UTC. Times before 1970 are clamped to the
+    /// epoch, and times after the end of year 65535 to its last second.
+    pub fn from_system_time(time: SystemTime) -> PngTime {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => 0,
+        };
+        let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+        // Converts days since the epoch to a proleptic Gregorian date, see
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719468;
+        let era = z / 146097;
+        let doe = z % 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        if year > u16::MAX as u64 {
+            return PngTime { year: u16::MAX, month: 12, day: 31, hour: 23, minute: 59, second: 59 };
+        }
+
+        PngTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+
+    pub fn now() -> PngTime {
+        PngTime::from_system_time(SystemTime::now())
+    }
+
+    /// libpng silently drops out of range values, so check them up front.
+    pub fn is_valid(&self) -> bool {
+        self.month >= 1 && self.month <= 12 && self.day >= 1 && self.day <= 31 &&
+            self.hour <= 23 && self.minute <= 59 && self.second <= 60
+    }
+
+    pub fn from_ffi(time: &ffi::png_time) -> PngTime {
+        PngTime {
+            year: time.year,
+            month: time.month,
+            day: time.day,
+            hour: time.hour,
+            minute: time.minute,
+            second: time.second,
+        }
+    }
+
+    pub fn to_ffi(self) -> ffi::png_time {
+        ffi::png_time {
+            year: self.year,
+            month: self.month,
+            day: self.day,
+            hour: self.hour,
+            minute: self.minute,
+            second: self.second,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::PngTime;
+
+    #[test]
+    fn test_from_system_time() {
+        let time = PngTime::from_system_time(UNIX_EPOCH + Duration::from_secs(951782400 + 3723));
+        assert_eq!(time, PngTime { year: 2000, month: 2, day: 29, hour: 1, minute: 2, second: 3 });
+        let time = PngTime::from_system_time(UNIX_EPOCH);
+        assert_eq!(time, PngTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 });
+        assert!(time.is_valid());
+    }
+
+    #[test]
+    fn test_from_system_time_clamps_year() {
+        let last = PngTime { year: 65535, month: 12, day: 31, hour: 23, minute: 59, second: 59 };
+        // 2005949145599 is 65535-12-31T23:59:59Z.
+        let time = PngTime::from_system_time(UNIX_EPOCH + Duration::from_secs(2005949145599));
+        assert_eq!(time, last);
+        let time = PngTime::from_system_time(UNIX_EPOCH + Duration::from_secs(2005949145600));
+        assert_eq!(time, last);
+        let time = PngTime::from_system_time(UNIX_EPOCH + Duration::from_secs(1 << 50));
+        assert_eq!(time, last);
+        assert!(time.is_valid());
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Decodes the PngSuite images shipped with libpng with every decode option
+//! and compares the pixels against hashes in `tests/pngsuite.golden`. Run
+//! with `PNGSUITE_BLESS=1` to rewrite the file after an intended change.
+//!
+//! The vendored subset has no interlaced or corrupt (`x*`) images, so
+//! corrupt files are derived from the valid ones instead.
+
+extern crate png;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::panic;
+use std::path::Path;
+
+use png::{Composite, DecodeOptions, GrayWeights, PixelFormat, PixelsByColorType};
+
+const SUITE: &str = "png-sys/libpng-1.6.16/contrib/pngsuite";
+const GOLDEN: &str = "tests/pngsuite.golden";
+
+fn options() -> Vec<(&'static str, DecodeOptions)> {
+    vec![
+        ("default", DecodeOptions::default()),
+        ("keep_16_bit", DecodeOptions { keep_16_bit: true, ..Default::default() }),
+        ("composite", DecodeOptions {
+            composite: Some(Composite::FileBackground([255, 0, 255])),
+            ..Default::default()
+        }),
+        ("shift", DecodeOptions { shift_to_significant_bits: true, keep_16_bit: true, ..Default::default() }),
+        ("to_gray", DecodeOptions { to_gray: Some(GrayWeights::Rec709), ..Default::default() }),
+        ("to_gray_16", DecodeOptions {
+            to_gray: Some(GrayWeights::Rec709),
+            keep_16_bit: true,
+            ..Default::default()
+        }),
+        ("bgra", DecodeOptions { format: Some(PixelFormat::BGRA8), ..Default::default() }),
+        ("argb", DecodeOptions { format: Some(PixelFormat::ARGB8), ..Default::default() }),
+        ("rgb16", DecodeOptions { format: Some(PixelFormat::RGB16), ..Default::default() }),
+    ]
+}
+
+fn suite_files() -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(SUITE).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".png"))
+        .collect();
+    names.sort();
+    names
+}
+
+fn read(path: &Path) -> Vec<u8> {
+    let mut data = vec![];
+    File::open(path).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+/// FNV-1a, to keep the golden file independent of std's hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Names the layout and returns the pixels as bytes, with 16-bit samples in
+/// big-endian order so that the hashes match on every platform.
+fn describe(pixels: &PixelsByColorType) -> (&'static str, Vec<u8>) {
+    fn bytes16(samples: &[u16]) -> Vec<u8> {
+        samples.iter().flat_map(|sample| sample.to_be_bytes().to_vec()).collect()
+    }
+    match *pixels {
+        PixelsByColorType::K8(ref p) => ("K8", p.clone()),
+        PixelsByColorType::KA8(ref p) => ("KA8", p.clone()),
+        PixelsByColorType::RGB8(ref p) => ("RGB8", p.clone()),
+        PixelsByColorType::RGBA8(ref p) => ("RGBA8", p.clone()),
+        PixelsByColorType::BGR8(ref p) => ("BGR8", p.clone()),
+        PixelsByColorType::BGRA8(ref p) => ("BGRA8", p.clone()),
+        PixelsByColorType::ARGB8(ref p) => ("ARGB8", p.clone()),
+        PixelsByColorType::K16(ref p) => ("K16", bytes16(p)),
+        PixelsByColorType::KA16(ref p) => ("KA16", bytes16(p)),
+        PixelsByColorType::RGB16(ref p) => ("RGB16", bytes16(p)),
+        PixelsByColorType::RGBA16(ref p) => ("RGBA16", bytes16(p)),
+        ref other => panic!("unexpected decoded layout {:?}", other),
+    }
+}
+
+/// One golden line for `data` decoded with `options`.
+fn result_line(data: &[u8], options: &DecodeOptions) -> String {
+    match png::load_png_from_memory_with_options(data, options) {
+        Ok(img) => {
+            let (layout, bytes) = describe(&img.pixels);
+            format!("{}x{} {} {:016x}", img.width, img.height, layout, fnv1a(&bytes))
+        }
+        Err(e) => format!("error {}", e),
+    }
+}
+
+#[test]
+fn test_pngsuite_golden() {
+    let mut results = BTreeMap::new();
+    for name in suite_files() {
+        let data = read(&Path::new(SUITE).join(&name));
+        for (option_name, options) in options() {
+            results.insert(format!("{} {}", name, option_name), result_line(&data, &options));
+        }
+    }
+
+    if env::var_os("PNGSUITE_BLESS").is_some() {
+        let mut file = File::create(GOLDEN).unwrap();
+        for (key, result) in &results {
+            writeln!(file, "{}: {}", key, result).unwrap();
+        }
+        return;
+    }
+
+    let mut golden = String::new();
+    File::open(GOLDEN).unwrap().read_to_string(&mut golden).unwrap();
+    let golden: BTreeMap<&str, &str> = golden.lines().map(|line| {
+        let mut parts = line.splitn(2, ": ");
+        (parts.next().unwrap(), parts.next().unwrap())
+    }).collect();
+
+    let mismatches: Vec<String> = results.iter().filter_map(|(key, result)| {
+        match golden.get(&key[..]) {
+            Some(expected) if expected == result => None,
+            Some(expected) => Some(format!("{}: expected {}, got {}", key, expected, result)),
+            None => Some(format!("{}: missing from {}", key, GOLDEN)),
+        }
+    }).collect();
+    assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+    assert_eq!(golden.len(), results.len());
+}
+
+#[test]
+fn test_pngsuite_agrees_with_defaults() {
+    // The 8-bit formats are rearrangements of the default RGBA8 output.
+    for name in suite_files() {
+        let data = read(&Path::new(SUITE).join(&name));
+        let rgba = match png::load_png_from_memory(&data).unwrap().pixels {
+            PixelsByColorType::RGBA8(pixels) => pixels,
+            _ => panic!("{}: expected RGBA8", name),
+        };
+        let options = DecodeOptions { format: Some(PixelFormat::BGRA8), ..Default::default() };
+        match png::load_png_from_memory_with_options(&data, &options).unwrap().pixels {
+            PixelsByColorType::BGRA8(bgra) => {
+                let swizzled: Vec<u8> = rgba.chunks(4).flat_map(|p| vec![p[2], p[1], p[0], p[3]]).collect();
+                assert!(bgra == swizzled, "{}", name);
+            }
+            _ => panic!("{}: expected BGRA8", name),
+        }
+
+        // The default output drops the low byte of 16-bit samples.
+        let options = DecodeOptions { keep_16_bit: true, ..Default::default() };
+        if let PixelsByColorType::RGBA16(wide) = png::load_png_from_memory_with_options(&data, &options).unwrap().pixels {
+            let narrowed: Vec<u8> = wide.iter().map(|&v| (v >> 8) as u8).collect();
+            assert!(narrowed == rgba, "{}", name);
+        }
+    }
+}
+
+#[test]
+fn test_pngsuite_reencodes() {
+    // bKGD and sBIT follow the decoded pixels, so decoding and encoding
+    // again keeps them along with the pixels.
+    let options = [
+        DecodeOptions::default(),
+        DecodeOptions { keep_16_bit: true, ..Default::default() },
+        DecodeOptions { to_gray: Some(GrayWeights::Rec709), ..Default::default() },
+    ];
+    for name in suite_files() {
+        let data = read(&Path::new(SUITE).join(&name));
+        for options in &options {
+            let mut img = png::load_png_from_memory_with_options(&data, options).unwrap();
+            if name.starts_with("ftb") {
+                assert!(img.metadata.background.is_some(), "{}", name);
+            }
+            let encoded = png::to_vec(&mut img).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            let decoded = png::load_png_from_memory_with_options(&encoded, options).unwrap();
+            assert!(decoded.pixels == img.pixels, "{}", name);
+            assert_eq!(decoded.metadata.background, img.metadata.background, "{}", name);
+            assert_eq!(decoded.metadata.significant_bits, img.metadata.significant_bits, "{}", name);
+        }
+    }
+}
+
+/// Decodes `data` with every option, failing if anything panics, and
+/// returns whether every decode failed.
+fn all_fail(data: &[u8]) -> bool {
+    options().iter().all(|(_, options)| {
+        match panic::catch_unwind(|| png::load_png_from_memory_with_options(data, options).is_err()) {
+            Ok(failed) => failed,
+            Err(_) => panic!("decoding panicked"),
+        }
+    })
+}
+
+#[test]
+fn test_corrupt_files_fail() {
+    let valid = read(&Path::new(SUITE).join("basn2c08.png"));
+    assert!(!all_fail(&valid));
+
+    // Bad signature.
+    let mut data = valid.clone();
+    data[1] = b'Q';
+    assert!(all_fail(&data));
+
+    // Bad IHDR CRC, then an invalid bit depth with a correct CRC.
+    let mut data = valid.clone();
+    data[29] ^= 0xff;
+    assert!(all_fail(&data));
+    let mut data = valid.clone();
+    data[24] = 3;
+    fix_crc(&mut data, 8);
+    assert!(all_fail(&data));
+
+    // Missing IDAT, or IDAT truncated at any point.
+    let idat = find_chunk(&valid, b"IDAT");
+    let mut data = valid[..idat].to_vec();
+    data.extend(&valid[valid.len() - 12..]);
+    assert!(all_fail(&data));
+    for len in (idat..valid.len() - 12).step_by(97) {
+        assert!(all_fail(&valid[..len]), "truncated to {} bytes", len);
+    }
+
+    // Corrupt zlib data with a correct CRC.
+    let mut data = valid.clone();
+    for byte in &mut data[idat + 8..idat + 40] {
+        *byte = 0xff;
+    }
+    fix_crc(&mut data, idat);
+    assert!(all_fail(&data));
+}
+
+#[test]
+fn test_flipped_bytes_do_not_panic() {
+    for name in &["basn0g01.png", "basn3p02.png", "basn0g16.png", "basn6a08.png"] {
+        let valid = read(&Path::new(SUITE).join(name));
+        for i in 8..valid.len() {
+            let mut data = valid.clone();
+            data[i] ^= 0x55;
+            all_fail(&data);
+        }
+    }
+}
+
+fn find_chunk(png: &[u8], chunk_type: &[u8; 4]) -> usize {
+    let mut offset = 8;
+    while &png[offset + 4..offset + 8] != chunk_type {
+        let len = u32::from_be_bytes([png[offset], png[offset + 1], png[offset + 2], png[offset + 3]]);
+        offset += len as usize + 12;
+    }
+    offset
+}
+
+/// Recomputes the CRC of the chunk starting at `offset`.
+fn fix_crc(png: &mut [u8], offset: usize) {
+    let len = u32::from_be_bytes([png[offset], png[offset + 1], png[offset + 2], png[offset + 3]]) as usize;
+    let mut crc = !0u32;
+    for &byte in &png[offset + 4..offset + 8 + len] {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    png[offset + 8 + len..offset + 12 + len].copy_from_slice(&(!crc).to_be_bytes());
+}
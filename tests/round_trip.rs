@@ -0,0 +1,255 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Randomized encode/decode round trips over every pixel layout and
+//! encoder setting. Set `ROUND_TRIP_SEED` to replay a reported failure and
+//! `ROUND_TRIP_CASES` to run more cases.
+//!
+//! Decoding cannot produce packed gray or palette layouts, so those are
+//! compared after expanding them to `RGBA8`.
+
+extern crate png;
+
+use std::env;
+
+use png::{BitDepth, DecodeOptions, EncodeOptions, Filter, Image, PaletteEntry, PixelFormat};
+use png::{PixelsByColorType, Strategy};
+
+/// xorshift64*, so that failures can be replayed from the seed alone.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+}
+
+const LAYOUTS: [&str; 18] = ["K8", "KA8", "RGB8", "RGBA8", "BGR8", "BGRA8", "ARGB8", "K16", "KA16", "RGB16",
+                             "RGBA16", "K1", "K2", "K4", "I1", "I2", "I4", "I8"];
+
+fn dimensions(rng: &mut Rng) -> (u32, u32) {
+    match rng.below(4) {
+        0 => (1, 1),
+        1 => (rng.below(3000) as u32 + 1000, rng.below(2) as u32 + 1),
+        2 => (rng.below(2) as u32 + 1, rng.below(300) as u32 + 100),
+        _ => (rng.below(40) as u32 + 1, rng.below(40) as u32 + 1),
+    }
+}
+
+/// Samples that are either random or drawn from a few distinct pixels, so
+/// that reducing the color type has something to do.
+fn samples(rng: &mut Rng, pixels: usize, channels: usize, max: u64) -> Vec<u64> {
+    let distinct = rng.pick(&[1, 2, 5, 300, 0]);
+    let random_pixel = |rng: &mut Rng| -> Vec<u64> {
+        // Gray, opaque and 8-bit-in-16 samples are common in practice.
+        let gray = rng.below(3) == 0;
+        let first = rng.next() % (max + 1);
+        (0..channels).map(|c| {
+            if channels == 2 && c == 1 || channels == 4 && c == 3 {
+                if rng.below(2) == 0 { max } else { rng.next() % (max + 1) }
+            } else if gray {
+                first
+            } else {
+                rng.next() % (max + 1)
+            }
+        }).collect()
+    };
+    if distinct == 0 {
+        return (0..pixels).flat_map(|_| random_pixel(rng)).collect();
+    }
+    let palette: Vec<Vec<u64>> = (0..distinct).map(|_| {
+        let pixel = random_pixel(rng);
+        if max == 0xffff && rng.below(2) == 0 { pixel.iter().map(|v| v / 257 * 257).collect() } else { pixel }
+    }).collect();
+    (0..pixels).flat_map(|_| palette[rng.below(distinct)].clone()).collect()
+}
+
+fn random_image(rng: &mut Rng, layout: &str, width: u32, height: u32) -> Image {
+    let count = width as usize * height as usize;
+    let bytes = |rng: &mut Rng, channels| samples(rng, count, channels, 0xff).iter().map(|&v| v as u8).collect();
+    let words = |rng: &mut Rng, channels| samples(rng, count, channels, 0xffff).iter().map(|&v| v as u16).collect();
+    let packed = |rng: &mut Rng, bits: usize, max: usize| -> Vec<u8> {
+        let stride = (width as usize * bits).div_ceil(8);
+        let mut data = vec![0; stride * height as usize];
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                let value = rng.below(max) as u8;
+                data[y * stride + x * bits / 8] |= value << (8 - bits - x * bits % 8);
+            }
+        }
+        data
+    };
+    let palette = |rng: &mut Rng, bits: u32| -> Vec<PaletteEntry> {
+        (0..rng.below(1 << bits) + 1).map(|_| {
+            let alpha = if rng.below(2) == 0 { 255 } else { rng.next() as u8 };
+            PaletteEntry { red: rng.next() as u8, green: rng.next() as u8, blue: rng.next() as u8, alpha }
+        }).collect()
+    };
+    let pixels = match layout {
+        "K8" => PixelsByColorType::K8(bytes(rng, 1)),
+        "KA8" => PixelsByColorType::KA8(bytes(rng, 2)),
+        "RGB8" => PixelsByColorType::RGB8(bytes(rng, 3)),
+        "RGBA8" => PixelsByColorType::RGBA8(bytes(rng, 4)),
+        "BGR8" => PixelsByColorType::BGR8(bytes(rng, 3)),
+        "BGRA8" => PixelsByColorType::BGRA8(bytes(rng, 4)),
+        "ARGB8" => PixelsByColorType::ARGB8(bytes(rng, 4)),
+        "K16" => PixelsByColorType::K16(words(rng, 1)),
+        "KA16" => PixelsByColorType::KA16(words(rng, 2)),
+        "RGB16" => PixelsByColorType::RGB16(words(rng, 3)),
+        "RGBA16" => PixelsByColorType::RGBA16(words(rng, 4)),
+        "K1" => PixelsByColorType::K1(packed(rng, 1, 2)),
+        "K2" => PixelsByColorType::K2(packed(rng, 2, 4)),
+        "K4" => PixelsByColorType::K4(packed(rng, 4, 16)),
+        _ => {
+            let bits = layout[1..].parse().unwrap();
+            let palette = palette(rng, bits);
+            let indices = packed(rng, bits as usize, palette.len());
+            match bits {
+                1 => PixelsByColorType::I1(indices, palette),
+                2 => PixelsByColorType::I2(indices, palette),
+                4 => PixelsByColorType::I4(indices, palette),
+                _ => PixelsByColorType::I8(indices, palette),
+            }
+        }
+    };
+    Image { width, height, pixels, metadata: Default::default() }
+}
+
+fn random_options(rng: &mut Rng) -> EncodeOptions {
+    EncodeOptions {
+        bit_depth: rng.pick(&[BitDepth::Native, BitDepth::Smallest]),
+        reduce: rng.below(2) == 0,
+        filter: rng.pick(&[Filter::Default, Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth,
+                           Filter::Adaptive]),
+        compression_level: rng.pick(&[None, Some(0), Some(1), Some(6), Some(9)]),
+        strategy: rng.pick(&[Strategy::Default, Strategy::Normal, Strategy::Filtered, Strategy::HuffmanOnly,
+                             Strategy::Rle, Strategy::Fixed]),
+        threads: rng.pick(&[0, 1, 3]),
+        ..Default::default()
+    }
+}
+
+/// The layout to decode to, or `None` if the pixels have to be expanded.
+fn decode_format(pixels: &PixelsByColorType) -> Option<PixelFormat> {
+    match *pixels {
+        PixelsByColorType::K8(_) => Some(PixelFormat::K8),
+        PixelsByColorType::KA8(_) => Some(PixelFormat::KA8),
+        PixelsByColorType::RGB8(_) => Some(PixelFormat::RGB8),
+        PixelsByColorType::RGBA8(_) => Some(PixelFormat::RGBA8),
+        PixelsByColorType::BGR8(_) => Some(PixelFormat::BGR8),
+        PixelsByColorType::BGRA8(_) => Some(PixelFormat::BGRA8),
+        PixelsByColorType::ARGB8(_) => Some(PixelFormat::ARGB8),
+        PixelsByColorType::K16(_) => Some(PixelFormat::K16),
+        PixelsByColorType::KA16(_) => Some(PixelFormat::KA16),
+        PixelsByColorType::RGB16(_) => Some(PixelFormat::RGB16),
+        PixelsByColorType::RGBA16(_) => Some(PixelFormat::RGBA16),
+        _ => None,
+    }
+}
+
+/// Expands packed gray and palette pixels to `RGBA8`.
+fn expand(img: &Image) -> Vec<u8> {
+    let (bits, palette, data) = match img.pixels {
+        PixelsByColorType::K1(ref data) => (1, None, data),
+        PixelsByColorType::K2(ref data) => (2, None, data),
+        PixelsByColorType::K4(ref data) => (4, None, data),
+        PixelsByColorType::I1(ref data, ref palette) => (1, Some(palette), data),
+        PixelsByColorType::I2(ref data, ref palette) => (2, Some(palette), data),
+        PixelsByColorType::I4(ref data, ref palette) => (4, Some(palette), data),
+        PixelsByColorType::I8(ref data, ref palette) => (8, Some(palette), data),
+        _ => unreachable!(),
+    };
+    let stride = (img.width as usize * bits).div_ceil(8);
+    let mut rgba = vec![];
+    for y in 0..img.height as usize {
+        for x in 0..img.width as usize {
+            let byte = data[y * stride + x * bits / 8];
+            let value = (byte >> (8 - bits - x * bits % 8)) as usize & ((1 << bits) - 1);
+            match palette {
+                Some(palette) => {
+                    let entry = palette[value];
+                    rgba.extend(&[entry.red, entry.green, entry.blue, entry.alpha]);
+                }
+                None => {
+                    let gray = (value * 255 / ((1 << bits) - 1)) as u8;
+                    rgba.extend(&[gray, gray, gray, 255]);
+                }
+            }
+        }
+    }
+    rgba
+}
+
+fn check_round_trip(rng: &mut Rng) -> Result<(), String> {
+    let layout = rng.pick(&LAYOUTS);
+    let (width, height) = dimensions(rng);
+    let img = random_image(rng, layout, width, height);
+    let options = random_options(rng);
+    let description = format!("{} {}x{}, {:?}", layout, img.width, img.height, options);
+
+    let data = png::to_vec_with_options(&mut img.clone(), &options)
+        .map_err(|e| format!("{}: encoding failed: {}", description, e))?;
+    let decode_options = DecodeOptions { format: decode_format(&img.pixels), keep_16_bit: true, ..Default::default() };
+    let decoded = png::load_png_from_memory_with_options(&data, &decode_options)
+        .map_err(|e| format!("{}: decoding failed: {}", description, e))?;
+
+    if (decoded.width, decoded.height) != (img.width, img.height) {
+        return Err(format!("{}: decoded as {}x{}", description, decoded.width, decoded.height));
+    }
+    let identical = match decode_format(&img.pixels) {
+        Some(_) => decoded.pixels == img.pixels,
+        None => decoded.pixels == PixelsByColorType::RGBA8(expand(&img)),
+    };
+    if identical { Ok(()) } else { Err(format!("{}: pixels differ", description)) }
+}
+
+#[test]
+fn test_random_round_trips() {
+    let seeds: Vec<u64> = match env::var("ROUND_TRIP_SEED") {
+        Ok(seed) => vec![seed.parse().unwrap()],
+        Err(_) => {
+            let cases = env::var("ROUND_TRIP_CASES").ok().map_or(200, |cases| cases.parse().unwrap());
+            (1..cases + 1).map(|case: u64| case.wrapping_mul(0x9e3779b97f4a7c15)).collect()
+        }
+    };
+    for seed in seeds {
+        if let Err(e) = check_round_trip(&mut Rng(seed)) {
+            panic!("{} (replay with ROUND_TRIP_SEED={})", e, seed);
+        }
+    }
+}
+
+#[test]
+fn test_every_layout_round_trips() {
+    // Default settings for each layout, including 1x1 and very wide images.
+    let mut rng = Rng(42);
+    for &layout in LAYOUTS.iter() {
+        for &(width, height) in &[(1, 1), (4099, 1), (3, 17)] {
+            let img = random_image(&mut rng, layout, width, height);
+            let data = png::to_vec(&mut img.clone()).unwrap();
+            let options = DecodeOptions { format: decode_format(&img.pixels), keep_16_bit: true, ..Default::default() };
+            let decoded = png::load_png_from_memory_with_options(&data, &options).unwrap();
+            match decode_format(&img.pixels) {
+                Some(_) => assert!(decoded.pixels == img.pixels, "{} {}x{}", layout, width, height),
+                None => assert!(decoded.pixels == PixelsByColorType::RGBA8(expand(&img)), "{} {}x{}", layout, width, height),
+            }
+        }
+    }
+}
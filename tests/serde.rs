@@ -0,0 +1,106 @@
+// Copyright 2015 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Round trips of images through a binary and a text serde format. Run with
+//! `cargo test --features serde-serialization`.
+
+#![cfg(feature="serde-serialization")]
+
+extern crate bincode;
+extern crate png;
+extern crate serde_json;
+
+use png::{Background, Exif, Image, Metadata, PaletteEntry, PixelsByColorType, PngTime, SignificantBits};
+
+fn image(pixels: PixelsByColorType) -> Image {
+    Image { width: 2, height: 2, pixels, metadata: Metadata::default() }
+}
+
+fn round_trip(image: &Image) {
+    let encoded = bincode::serialize(image).unwrap();
+    assert_eq!(&bincode::deserialize::<Image>(&encoded).unwrap(), image);
+    let encoded = serde_json::to_string(image).unwrap();
+    assert_eq!(&serde_json::from_str::<Image>(&encoded).unwrap(), image);
+}
+
+#[test]
+fn test_pixels() {
+    let palette = vec![PaletteEntry { red: 1, green: 2, blue: 3, alpha: 4 }];
+    let images = vec![
+        image(PixelsByColorType::K8(vec![0, 1, 2, 3])),
+        image(PixelsByColorType::KA8((0..8).collect())),
+        image(PixelsByColorType::RGB8((0..12).collect())),
+        image(PixelsByColorType::RGBA8((0..16).collect())),
+        image(PixelsByColorType::BGR8((0..12).collect())),
+        image(PixelsByColorType::BGRA8((0..16).collect())),
+        image(PixelsByColorType::ARGB8((0..16).collect())),
+        image(PixelsByColorType::K16(vec![0, 0x0102, 0xfffe, 0xffff])),
+        image(PixelsByColorType::KA16((0..8).map(|i| i * 0x1001).collect())),
+        image(PixelsByColorType::RGB16((0..12).map(|i| i * 0x1001).collect())),
+        image(PixelsByColorType::RGBA16((0..16).map(|i| i * 0x1001).collect())),
+        image(PixelsByColorType::K1(vec![0x80, 0x40])),
+        image(PixelsByColorType::K2(vec![0xc0, 0x30])),
+        image(PixelsByColorType::K4(vec![0xf0, 0x0f])),
+        image(PixelsByColorType::I1(vec![0, 0], palette.clone())),
+        image(PixelsByColorType::I2(vec![0, 0], palette.clone())),
+        image(PixelsByColorType::I4(vec![0, 0], palette.clone())),
+        image(PixelsByColorType::I8(vec![0, 0, 0, 0], palette)),
+    ];
+    for image in &images {
+        round_trip(image);
+    }
+}
+
+#[test]
+fn test_metadata() {
+    let mut image = image(PixelsByColorType::RGBA8(vec![0; 16]));
+    image.metadata = Metadata {
+        exif: Some(Exif::new(vec![b'M', b'M', 0, 42, 0, 0, 0, 8])),
+        time: Some(PngTime { year: 2015, month: 6, day: 30, hour: 23, minute: 59, second: 60 }),
+        background: Some(Background::Rgb(1, 2, 3)),
+        significant_bits: Some(SignificantBits { red: 5, green: 6, blue: 5, gray: 0, alpha: 8 }),
+        ..Default::default()
+    };
+    round_trip(&image);
+}
+
+#[test]
+fn test_had_color_not_serialized() {
+    let mut image = image(PixelsByColorType::K8(vec![0; 4]));
+    image.metadata.had_color = true;
+    let json = serde_json::to_string(&image).unwrap();
+    assert!(!json.contains("had_color"));
+    assert!(!serde_json::from_str::<Image>(&json).unwrap().metadata.had_color);
+    let encoded = bincode::serialize(&image).unwrap();
+    assert!(!bincode::deserialize::<Image>(&encoded).unwrap().metadata.had_color);
+}
+
+#[test]
+fn test_decoded() {
+    let image = png::load_png("test/servo-screenshot.png").unwrap();
+    round_trip(&image);
+}
+
+#[test]
+fn test_16_bit_pixels_as_bytes() {
+    // Stored as one string of little-endian bytes, so the length bincode
+    // writes first counts bytes rather than samples.
+    let image = image(PixelsByColorType::RGBA16(vec![0x1234; 16]));
+    let encoded = bincode::serialize(&image).unwrap();
+    let mut expected = 32u64.to_le_bytes().to_vec();
+    expected.extend(vec![0x34, 0x12].into_iter().cycle().take(32));
+    assert!(encoded.windows(expected.len()).any(|window| window == &expected[..]));
+}
+
+#[test]
+fn test_odd_16_bit_bytes() {
+    let json = r#"{"width":1,"height":1,"pixels":{"K16":[1,2,3]},"metadata":{"exif":null,"time":null,
+                  "background":null,"significant_bits":null}}"#;
+    assert!(serde_json::from_str::<Image>(json).is_err());
+}